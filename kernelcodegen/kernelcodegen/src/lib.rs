@@ -7,3 +7,377 @@ pub use macros::generate_kernel;
 pub use kernelcodegen_types::ComputeKernel;
 #[cfg(not(target_arch = "spirv"))]
 pub extern crate wgpu;
+
+/// Atomic read-modify-write operations on storage buffer words. On the GPU these are device scoped
+/// atomics, while on the host kernels are run one invocation at a time so a plain
+/// read-modify-write is enough.
+pub mod atomic {
+    #[cfg(target_arch = "spirv")]
+    use spirv_std::{arch, memory};
+
+    /// Adds `value` to `*ptr`, returning the previous value
+    pub fn add(ptr: &mut u32, value: u32) -> u32 {
+        #[cfg(target_arch = "spirv")]
+        unsafe {
+            arch::atomic_i_add::<
+                u32,
+                { memory::Scope::Device as u32 },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+            >(ptr, value)
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            let prev = *ptr;
+            *ptr = prev.wrapping_add(value);
+            prev
+        }
+    }
+
+    /// Subtracts `value` from `*ptr`, returning the previous value
+    pub fn sub(ptr: &mut u32, value: u32) -> u32 {
+        #[cfg(target_arch = "spirv")]
+        unsafe {
+            arch::atomic_i_sub::<
+                u32,
+                { memory::Scope::Device as u32 },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+            >(ptr, value)
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            let prev = *ptr;
+            *ptr = prev.wrapping_sub(value);
+            prev
+        }
+    }
+
+    /// Sets the bits of `value` in `*ptr`, returning the previous value
+    pub fn or(ptr: &mut u32, value: u32) -> u32 {
+        #[cfg(target_arch = "spirv")]
+        unsafe {
+            arch::atomic_or::<
+                u32,
+                { memory::Scope::Device as u32 },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+            >(ptr, value)
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            let prev = *ptr;
+            *ptr = prev | value;
+            prev
+        }
+    }
+
+    /// Sets `*ptr` to the larger of `*ptr` and `value`, returning the previous value
+    pub fn max(ptr: &mut u32, value: u32) -> u32 {
+        #[cfg(target_arch = "spirv")]
+        unsafe {
+            arch::atomic_u_max::<
+                u32,
+                { memory::Scope::Device as u32 },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+            >(ptr, value)
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            let prev = *ptr;
+            *ptr = core::cmp::max(prev, value);
+            prev
+        }
+    }
+
+    /// Stores `value` in `*ptr` if it holds `comparator`, returning the previous value
+    pub fn compare_exchange(ptr: &mut u32, value: u32, comparator: u32) -> u32 {
+        #[cfg(target_arch = "spirv")]
+        unsafe {
+            arch::atomic_compare_exchange::<
+                u32,
+                { memory::Scope::Device as u32 },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+                { memory::Semantics::OUTPUT_MEMORY.bits() },
+            >(ptr, value, comparator)
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            let prev = *ptr;
+            if prev == comparator {
+                *ptr = value;
+            }
+            prev
+        }
+    }
+}
+
+/// Byte access to text in storage buffers. WGSL has no 8-bit types, so the kernels bind text as
+/// words holding four bytes each, least significant byte first, and extract the bytes they need.
+/// Bytes are returned as u32 so that no 8-bit values reach the shader either.
+pub mod bytes {
+    /// Byte `i` of the text packed in `words`
+    pub fn get(words: &[u32], i: usize) -> u32 {
+        (words[i / 4] >> (8 * (i % 4))) & 0xff
+    }
+
+    /// Packs `bytes` into words as the kernels read them, zero padded to at least one word since
+    /// bindings can't be empty
+    #[cfg(not(target_arch = "spirv"))]
+    pub fn pack(bytes: &[u8]) -> Vec<u32> {
+        let mut words = vec![0; bytes.len().div_ceil(4).max(1)];
+        for (word, chunk) in words.iter_mut().zip(bytes.chunks(4)) {
+            let mut le = [0; 4];
+            le[..chunk.len()].copy_from_slice(chunk);
+            *word = u32::from_le_bytes(le);
+        }
+        words
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn packs_and_gets_bytes() {
+            let text = b"hello, world";
+            for len in 0..text.len() {
+                let words = pack(&text[..len]);
+                assert_eq!(words.len(), len.div_ceil(4).max(1));
+                for (i, b) in text[..len].iter().enumerate() {
+                    assert_eq!(get(&words, i), *b as u32);
+                }
+            }
+        }
+    }
+}
+
+/// A device-wide exclusive prefix sum over storage buffer words.
+///
+/// The values to scan are level 0 of a `sums` buffer, followed by a level for the block totals of
+/// the level before it, each `BLOCK` times shorter, up to a level with a single entry: the total
+/// of every value. Each level is scanned in place by its own dispatch, with a workgroup of `BLOCK`
+/// invocations per block, which also writes the block's total to the next level. The prefix sum
+/// of a value is then the sum of the entries covering it on every scanned level, see `prefix`.
+///
+/// On the GPU a workgroup scans its block in workgroup memory with the Hillis-Steele algorithm,
+/// synchronizing its invocations with barriers. The host runs the invocations of a workgroup one
+/// at a time and in order, so each of them adds its value to the running sum left by the one
+/// before instead.
+pub mod scan {
+    #[cfg(target_arch = "spirv")]
+    use spirv_std::arch;
+
+    /// Entries scanned by each workgroup, which must have as many invocations
+    pub const BLOCK: u32 = 256;
+    /// Workgroup memory for scanning a block, with an entry for every invocation
+    pub type Shared = [u32; BLOCK as usize];
+    /// Most levels that `n` values can need to be scanned, since `n` is below `BLOCK.pow(4)`
+    pub const MAX_LEVELS: u32 = 4;
+
+    /// Number of levels to scan for `n` values, after which the next level holds only the total
+    pub fn levels(n: u32) -> u32 {
+        let mut len = n;
+        let mut levels = 0;
+        while len > 1 {
+            len = len.div_ceil(BLOCK);
+            levels += 1;
+        }
+        levels
+    }
+
+    /// Offset in `sums` of `level` of the scan of `n` values, and its number of entries
+    pub fn level_range(n: u32, level: u32) -> (u32, u32) {
+        let mut offset = 0;
+        let mut len = n;
+        for _ in 0..level {
+            offset += len;
+            len = len.div_ceil(BLOCK);
+        }
+        (offset, len)
+    }
+
+    /// Offset in `sums` of the total of `n` values
+    pub fn total_offset(n: u32) -> u32 {
+        level_range(n, levels(n)).0
+    }
+
+    /// Number of words in `sums` for `n` values
+    pub fn sums_len(n: u32) -> u32 {
+        total_offset(n) + 1
+    }
+
+    /// Number of workgroups that scan `level`, one per block
+    pub fn level_blocks(n: u32, level: u32) -> u32 {
+        level_range(n, level).1.div_ceil(BLOCK)
+    }
+
+    /// The sum of the values of the invocations of the workgroup up to and including `lid`. Every
+    /// invocation of the workgroup must call this, with the same `shared`.
+    pub fn workgroup_scan(shared: &mut Shared, lid: u32, value: u32) -> u32 {
+        let lid = lid as usize;
+        #[cfg(target_arch = "spirv")]
+        {
+            shared[lid] = value;
+            let mut offset = 1;
+            while offset < BLOCK as usize {
+                unsafe { arch::workgroup_memory_barrier_with_group_sync() };
+                let sum = step(shared, lid, offset);
+                unsafe { arch::workgroup_memory_barrier_with_group_sync() };
+                shared[lid] = sum;
+                offset *= 2;
+            }
+            shared[lid]
+        }
+        #[cfg(not(target_arch = "spirv"))]
+        {
+            shared[lid] = if lid == 0 {
+                value
+            } else {
+                shared[lid - 1] + value
+            };
+            shared[lid]
+        }
+    }
+
+    /// Entry `lid` of a Hillis-Steele scan after the step that adds up entries `offset` apart
+    #[cfg(any(target_arch = "spirv", test))]
+    fn step(shared: &Shared, lid: usize, offset: usize) -> u32 {
+        if lid >= offset {
+            shared[lid - offset] + shared[lid]
+        } else {
+            shared[lid]
+        }
+    }
+
+    /// Scans the block of `level` that invocation `id` belongs to in place, and writes its total
+    /// to the next level. Must be called by every invocation of the workgroup, `lid` being its
+    /// index within it. Blocks past the end of the level are ignored, so the dispatch can be
+    /// rounded up.
+    pub fn scan_block(
+        sums: &mut [u32],
+        shared: &mut Shared,
+        n: u32,
+        level: u32,
+        id: u32,
+        lid: u32,
+    ) {
+        let (offset, len) = level_range(n, level);
+        let block = id / BLOCK;
+        // Entries past the end of the level count as 0, since the whole workgroup has to take
+        // part in the scan
+        let value = if id < len {
+            sums[(offset + id) as usize]
+        } else {
+            0
+        };
+        let sum = workgroup_scan(shared, lid, value);
+        if id < len {
+            sums[(offset + id) as usize] = sum - value;
+        }
+        if lid == BLOCK - 1 && block < len.div_ceil(BLOCK) {
+            sums[(offset + len + block) as usize] = sum;
+        }
+    }
+
+    /// The sum of the values before value `index`, once every level has been scanned
+    pub fn prefix(sums: &[u32], n: u32, index: u32) -> u32 {
+        let mut acc = 0;
+        let mut offset = 0;
+        let mut len = n;
+        let mut index = index;
+        for _ in 0..levels(n) {
+            acc += sums[(offset + index) as usize];
+            offset += len;
+            len = len.div_ceil(BLOCK);
+            index /= BLOCK;
+        }
+        acc
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Scans `values` level by level as the host runs the kernels' dispatches, with one extra
+        /// block per level as if the dispatch had been rounded up
+        fn scan(values: &[u32]) -> Vec<u32> {
+            let n = values.len() as u32;
+            let mut sums = vec![0; sums_len(n) as usize];
+            sums[..values.len()].copy_from_slice(values);
+            for level in 0..levels(n) {
+                for block in 0..=level_blocks(n, level) {
+                    let mut shared = [0; BLOCK as usize];
+                    for lid in 0..BLOCK {
+                        scan_block(&mut sums, &mut shared, n, level, block * BLOCK + lid, lid);
+                    }
+                }
+            }
+            sums
+        }
+
+        #[test]
+        fn counts_levels() {
+            assert_eq!(levels(0), 0);
+            assert_eq!(levels(1), 0);
+            assert_eq!(levels(2), 1);
+            assert_eq!(levels(BLOCK), 1);
+            assert_eq!(levels(BLOCK + 1), 2);
+            assert_eq!(levels(BLOCK * BLOCK), 2);
+            assert_eq!(levels(BLOCK * BLOCK + 1), 3);
+            assert_eq!(levels(u32::MAX), MAX_LEVELS);
+        }
+
+        #[test]
+        fn lays_out_levels() {
+            let n = BLOCK * BLOCK + 1;
+            assert_eq!(level_range(n, 0), (0, n));
+            assert_eq!(level_range(n, 1), (n, BLOCK + 1));
+            assert_eq!(level_range(n, 2), (n + BLOCK + 1, 2));
+            assert_eq!(total_offset(n), n + BLOCK + 3);
+            assert_eq!(sums_len(n), n + BLOCK + 4);
+            assert_eq!(level_blocks(n, 0), BLOCK + 1);
+            assert_eq!(level_blocks(n, 2), 1);
+        }
+
+        #[test]
+        fn steps_through_a_block_in_lockstep() {
+            let mut shared: Shared = core::array::from_fn(|i| (i as u32 * 7 + 3) % 11);
+            let mut expected = shared;
+            for i in 1..expected.len() {
+                expected[i] += expected[i - 1];
+            }
+            // Every invocation reads its entries before any of them writes, as the barriers
+            // ensure on the GPU
+            let mut offset = 1;
+            while offset < BLOCK as usize {
+                shared = core::array::from_fn(|lid| step(&shared, lid, offset));
+                offset *= 2;
+            }
+            assert_eq!(shared, expected);
+        }
+
+        #[test]
+        fn scans_across_levels() {
+            let mut state = 1u32;
+            for n in [0, 1, 2, 255, 256, 257, 1000, 65535, 65536, 65537, 300_000] {
+                let values: Vec<u32> = (0..n)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                        state >> 28
+                    })
+                    .collect();
+                let sums = scan(&values);
+                let mut expected = 0;
+                for (index, value) in values.iter().enumerate() {
+                    assert_eq!(
+                        prefix(&sums, n, index as u32),
+                        expected,
+                        "n {} index {}",
+                        n,
+                        index
+                    );
+                    expected += value;
+                }
+                assert_eq!(sums[total_offset(n) as usize], expected, "n {}", n);
+            }
+        }
+    }
+}
@@ -267,6 +267,11 @@ pub fn generate_kernel(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let module_name = Literal::string(&modname);
     let compute_pipeline_label = Literal::string(&format!("{}_compute_pipeline", modname));
     let entrypt_label = Literal::string(&entrypt);
+    let n_storage_buffers = args
+        .iter()
+        .filter(|arg| matches!(arg.binding, BindingType::Storage))
+        .count();
+    let n_storage_buffers = Literal::u32_unsuffixed(n_storage_buffers as u32);
     let workgroup_dim_tuple = TokenTree::from(Group::new(Delimiter::Parenthesis, {
         let mut tuple_tokens: Vec<proc_macro2::TokenTree> = Vec::new();
         tuple_tokens.push(TokenTree::from(Literal::u32_unsuffixed(workgroup_dim.0)));
@@ -286,19 +291,32 @@ pub fn generate_kernel(_attr: TokenStream, item: TokenStream) -> TokenStream {
             use wgpu::Device;
             use core::num::NonZeroU64;
 
-            pub fn new(device: &Device, shader_bytes: &[u8]) -> ComputeKernel {
+            pub const WORKGROUP_DIM: (u32, u32, u32) = #workgroup_dim_tuple;
+            /// Number of storage buffers bound to the kernel
+            pub const STORAGE_BUFFERS: u32 = #n_storage_buffers;
+
+            /// Creates the kernel from its SPIR-V, or from `wgsl` if the device can't take SPIR-V
+            /// as it is
+            pub fn new(device: &Device, shader_bytes: &[u8], wgsl: &str) -> ComputeKernel {
                 let bind_group_layout = device.create_bind_group_layout #bind_group_layout_args;
                 let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some(#layout_label),
                     bind_group_layouts: &[&bind_group_layout],
                     push_constant_ranges: &[],
                 });
-                let spirv = std::borrow::Cow::Owned(wgpu::util::make_spirv_raw(shader_bytes).into_owned());
-                let shader_binary = wgpu::ShaderModuleDescriptorSpirV {
-                    label: Some(#module_name),
-                    source: spirv
+                let module = if device.features().contains(wgpu::Features::SPIRV_SHADER_PASSTHROUGH) {
+                    let spirv = std::borrow::Cow::Owned(wgpu::util::make_spirv_raw(shader_bytes).into_owned());
+                    let shader_binary = wgpu::ShaderModuleDescriptorSpirV {
+                        label: Some(#module_name),
+                        source: spirv
+                    };
+                    unsafe { device.create_shader_module_spirv(&shader_binary) }
+                } else {
+                    device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                        label: Some(#module_name),
+                        source: wgpu::ShaderSource::Wgsl(wgsl.into()),
+                    })
                 };
-                let module = unsafe { device.create_shader_module_spirv(&shader_binary) };
                 let compute_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                     label: Some(#compute_pipeline_label),
                     layout: Some(&pipeline_layout),
@@ -308,7 +326,7 @@ pub fn generate_kernel(_attr: TokenStream, item: TokenStream) -> TokenStream {
                     cache: None
                 });
 
-                let workgroup_dim = #workgroup_dim_tuple;
+                let workgroup_dim = WORKGROUP_DIM;
                 ComputeKernel {
                     bind_group_layout,
                     pipeline_layout,
@@ -1,38 +1,73 @@
 #![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
+//! Counts the lines in each thread's range of the input, and turns the counts into the offset of
+//! each thread's first line so that getcharpos writes the line offsets in file order.
+//!
+//! The offsets are an exclusive prefix sum of the counts, see `kernelcodegen::scan`:
+//!   1. PASS_COUNT: every thread writes the number of lines in its range to level 0 of `sums`
+//!   2. PASS_SCAN + level: every workgroup scans a block of the level, for each level that
+//!      `scan::levels` gives for the number of threads
+//!
+//! The total number of lines is then at `scan::total_offset`.
 use glam::UVec3;
-use kernelcodegen::generate_kernel;
-use spirv_std::{arch, glam, memory, spirv};
+use kernelcodegen::{bytes, generate_kernel, scan};
+use spirv_std::{glam, spirv};
+
+// Values of the `pass` uniform
+pub const PASS_COUNT: u32 = 0;
+/// Scans level 0 of `sums`, PASS_SCAN + 1 scans level 1 and so on
+pub const PASS_SCAN: u32 = 1;
 
 #[generate_kernel()]
 #[spirv(compute(threads(256)))]
 pub fn main_cc(
     #[spirv(local_invocation_id)] lid: UVec3,
     #[spirv(global_invocation_id)] id: UVec3,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &mut [u8],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &[u32],
     #[spirv(uniform, descriptor_set = 0, binding = 1)] chunk_size: &u32,
     #[spirv(uniform, descriptor_set = 0, binding = 2)] data_len: &u32,
-    #[spirv(uniform, descriptor_set = 0, binding = 3)] char: &u8,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] count: &mut [u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 3)] char: &u32,
+    // The count of lines of every thread, followed by the levels of their scan. Must hold
+    // `scan::sums_len(*n_threads)` words.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] sums: &mut [u32],
+    // Quote character, or 0 if fields can't be quoted. Matches of `char` inside quotes are
+    // skipped.
+    #[spirv(uniform, descriptor_set = 0, binding = 5)] quote: &u32,
+    // Bit `id.x` is set if the thread's range starts inside quotes, see quoteparity. Only read
+    // if `quote` is set.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] in_quotes: &[u32],
+    // One of the PASS_* constants
+    #[spirv(uniform, descriptor_set = 0, binding = 7)] pass: &u32,
+    // Number of threads dispatched for PASS_COUNT
+    #[spirv(uniform, descriptor_set = 0, binding = 8)] n_threads: &u32,
+    #[spirv(workgroup)] shared: &mut scan::Shared,
 ) {
     let index = id.x as usize;
-    let lindex = lid.x as usize;
+
+    if *pass != PASS_COUNT {
+        scan::scan_block(sums, shared, *n_threads, *pass - PASS_SCAN, id.x, lid.x);
+        return;
+    }
 
     let start: usize = index * (*chunk_size as usize);
 
+    let mut quoted = *quote != 0 && (in_quotes[index / 32] >> (index % 32)) & 1 == 1;
     let mut acc = 0;
     for i in start..(start + *chunk_size as usize) {
-        if i < *data_len as usize && input[i] == *char {
-            acc += 1;
+        if i < *data_len as usize {
+            let c = bytes::get(input, i);
+            if *quote != 0 && c == *quote {
+                quoted = !quoted;
+            } else if c == *char && !quoted {
+                acc += 1;
+            }
         }
     }
 
-    // Each thread per workgroup adds to a unique index in the output - this needs to be
-    // synchronized across all workgroup instances though.
-    unsafe {
-        arch::atomic_i_add::<
-            u32,
-            { memory::Scope::Device as u32 },
-            { memory::Semantics::OUTPUT_MEMORY.bits() },
-        >(&mut count[lindex], acc)
-    };
+    // Every thread has its own entry, so no synchronization is needed
+    sums[index] = acc;
 }
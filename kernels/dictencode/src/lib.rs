@@ -0,0 +1,239 @@
+#![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
+//! Dictionary encodes the strings located by parsecsv. Every distinct string is numbered in the
+//! order of its first occurrence, which takes these dispatches:
+//!   1. PASS_INSERT: every row is inserted into an open addressing hash table, whose slots hold
+//!      the first row to claim them and the lowest row with the same string
+//!   2. PASS_COUNT: every thread writes the number of rows in its range that are the first
+//!      occurrence of their string to level 0 of `sums`
+//!   3. PASS_SCAN + level: every workgroup scans a block of the level, for each level that
+//!      `scan::levels` gives for the number of threads, see `kernelcodegen::scan`
+//!   4. PASS_ASSIGN: every thread numbers the first occurrences in its range, starting from its
+//!      prefix sum
+//!   5. PASS_RESOLVE: every row looks up the number of its string through its slot
+//!
+//! The number of distinct strings is then at `scan::total_offset`, and their strings are
+//! compacted by gatherstrings.
+use glam::UVec3;
+use kernelcodegen::{atomic, bytes, generate_kernel, scan};
+use spirv_std::{glam, spirv};
+
+// Values of the `pass` uniform
+pub const PASS_INSERT: u32 = 0;
+pub const PASS_COUNT: u32 = 1;
+/// Scans level 0 of `sums`, PASS_SCAN + 1 scans level 1 and so on
+pub const PASS_SCAN: u32 = 2;
+pub const PASS_ASSIGN: u32 = PASS_SCAN + scan::MAX_LEVELS;
+pub const PASS_RESOLVE: u32 = PASS_ASSIGN + 1;
+
+/// Must match parsecsv::STRING_QUOTED
+const STRING_QUOTED: u32 = 1 << 31;
+
+/// Number of slots of the hash table for `nlines` rows. The table is never more than half full,
+/// and its size is a power of two so that hashes can be masked into it.
+pub fn table_size(nlines: u32) -> u32 {
+    core::cmp::max(2 * nlines, 2).next_power_of_two()
+}
+
+/// Byte `pos` of the residual followed by the input
+fn byte_at(residual: &[u32], residual_len: usize, input: &[u32], pos: usize) -> u32 {
+    if pos < residual_len {
+        bytes::get(residual, pos)
+    } else {
+        bytes::get(input, pos - residual_len)
+    }
+}
+
+/// Position of the next byte of a string after the one at `pos`, given that the byte at `pos`
+/// is the start of a doubled quote if the string is quoted
+fn skip_escape(quoted: bool, c: u32, quote: u32, pos: usize) -> usize {
+    if quoted && c == quote {
+        pos + 1
+    } else {
+        pos
+    }
+}
+
+/// FNV-1a hash of the string of `row`
+fn hash(
+    residual: &[u32],
+    residual_len: usize,
+    input: &[u32],
+    quote: u32,
+    strings: &[u32],
+    row: usize,
+) -> u32 {
+    let quoted = strings[row * 2 + 1] & STRING_QUOTED != 0;
+    let len = strings[row * 2 + 1] & !STRING_QUOTED;
+    let mut pos = strings[row * 2] as usize;
+    let mut hash: u32 = 0x811c9dc5;
+    for _ in 0..len {
+        pos = skip_escape(
+            quoted,
+            byte_at(residual, residual_len, input, pos),
+            quote,
+            pos,
+        );
+        hash ^= byte_at(residual, residual_len, input, pos);
+        hash = hash.wrapping_mul(0x01000193);
+        pos += 1;
+    }
+    hash
+}
+
+/// Whether the strings of rows `a` and `b` are equal once unquoted
+fn strings_eq(
+    residual: &[u32],
+    residual_len: usize,
+    input: &[u32],
+    quote: u32,
+    strings: &[u32],
+    a: usize,
+    b: usize,
+) -> bool {
+    let len = strings[a * 2 + 1] & !STRING_QUOTED;
+    if len != strings[b * 2 + 1] & !STRING_QUOTED {
+        return false;
+    }
+    let a_quoted = strings[a * 2 + 1] & STRING_QUOTED != 0;
+    let b_quoted = strings[b * 2 + 1] & STRING_QUOTED != 0;
+    let mut a_pos = strings[a * 2] as usize;
+    let mut b_pos = strings[b * 2] as usize;
+    for _ in 0..len {
+        a_pos = skip_escape(
+            a_quoted,
+            byte_at(residual, residual_len, input, a_pos),
+            quote,
+            a_pos,
+        );
+        b_pos = skip_escape(
+            b_quoted,
+            byte_at(residual, residual_len, input, b_pos),
+            quote,
+            b_pos,
+        );
+        if byte_at(residual, residual_len, input, a_pos)
+            != byte_at(residual, residual_len, input, b_pos)
+        {
+            return false;
+        }
+        a_pos += 1;
+        b_pos += 1;
+    }
+    true
+}
+
+fn is_valid(validity: &[u32], row: usize) -> bool {
+    (validity[row / 32] >> (row % 32)) & 1 == 1
+}
+
+#[generate_kernel()]
+#[spirv(compute(threads(256)))]
+pub fn main_dictencode(
+    #[spirv(local_invocation_id)] lid: UVec3,
+    #[spirv(global_invocation_id)] id: UVec3,
+    // The residual that was bound to parsecsv
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] residual: &[u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] residual_len: &u32,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] input: &[u32],
+    // min(chunk_lines, rows - chunk_lines * id.x) is the number of rows to process per thread
+    #[spirv(uniform, descriptor_set = 0, binding = 3)] chunk_lines: &u32,
+    // One of the PASS_* constants
+    #[spirv(uniform, descriptor_set = 0, binding = 4)] pass: &u32,
+    // Quote character the strings were parsed with
+    #[spirv(uniform, descriptor_set = 0, binding = 5)] quote: &u32,
+    // The (offset, length) pairs written by parsecsv for a TYPE_STRING column
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] strings: &[u32],
+    // The validity written by parsecsv, null rows are left out of the dictionary
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] validity: &[u32],
+    // table_size(rows) slots, each holding 1 + the row that claimed it. PASS_ASSIGN replaces the
+    // row by the number of the slot's string. Must be zeroed by the caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 8)] table: &mut [u32],
+    // The bitwise not of the lowest row with each slot's string, so that a zeroed buffer holds
+    // no row. Must be zeroed by the caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] first_rows: &mut [u32],
+    // The number of first occurrences in each thread's range, followed by the levels of their
+    // scan. Must hold `scan::sums_len(*n_threads)` words.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] sums: &mut [u32],
+    // Number of threads dispatched for every pass but the scan
+    #[spirv(uniform, descriptor_set = 0, binding = 11)] n_threads: &u32,
+    // The (offset, length) pair of every distinct string, as in `strings`
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] entries: &mut [u32],
+    // The slot of every row, replaced by the number of its string by PASS_RESOLVE. Null rows
+    // are left as 0. Must be zeroed by the caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 13)] codes: &mut [u32],
+    #[spirv(workgroup)] shared: &mut scan::Shared,
+) {
+    let index = id.x as usize;
+    let residual_len = *residual_len as usize;
+    let rows = codes.len();
+    let first = index * (*chunk_lines as usize);
+
+    if *pass == PASS_INSERT {
+        let mask = table.len() - 1;
+        for row in first..(first + *chunk_lines as usize) {
+            if row >= rows || !is_valid(validity, row) {
+                continue;
+            }
+            let hash = hash(residual, residual_len, input, *quote, strings, row);
+            let mut slot = hash as usize & mask;
+            loop {
+                let claimed = atomic::compare_exchange(&mut table[slot], row as u32 + 1, 0);
+                if claimed == 0
+                    || strings_eq(
+                        residual,
+                        residual_len,
+                        input,
+                        *quote,
+                        strings,
+                        row,
+                        claimed as usize - 1,
+                    )
+                {
+                    break;
+                }
+                slot = (slot + 1) & mask;
+            }
+            codes[row] = slot as u32;
+            atomic::max(&mut first_rows[slot], !(row as u32));
+        }
+    } else if *pass == PASS_COUNT {
+        let mut acc = 0;
+        for row in first..(first + *chunk_lines as usize) {
+            if row < rows
+                && is_valid(validity, row)
+                && !first_rows[codes[row] as usize] == row as u32
+            {
+                acc += 1;
+            }
+        }
+        // Every thread has its own entry, so no synchronization is needed
+        sums[index] = acc;
+    } else if *pass == PASS_ASSIGN {
+        let mut entry = scan::prefix(sums, *n_threads, id.x);
+        for row in first..(first + *chunk_lines as usize) {
+            if row >= rows || !is_valid(validity, row) {
+                continue;
+            }
+            let slot = codes[row] as usize;
+            if !first_rows[slot] == row as u32 {
+                table[slot] = entry;
+                entries[entry as usize * 2] = strings[row * 2];
+                entries[entry as usize * 2 + 1] = strings[row * 2 + 1];
+                entry += 1;
+            }
+        }
+    } else if *pass == PASS_RESOLVE {
+        for row in first..(first + *chunk_lines as usize) {
+            if row < rows && is_valid(validity, row) {
+                codes[row] = table[codes[row] as usize];
+            }
+        }
+    } else {
+        scan::scan_block(sums, shared, *n_threads, *pass - PASS_SCAN, id.x, lid.x);
+    }
+}
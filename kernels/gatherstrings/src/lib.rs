@@ -0,0 +1,151 @@
+#![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
+//! Compacts the strings located by parsecsv into a contiguous values buffer, laid out as an Arrow
+//! string array. The offsets of the strings in the values buffer are an exclusive prefix sum of
+//! their lengths, see `kernelcodegen::scan`:
+//!   1. PASS_COUNT: every thread writes the total length of the strings in its range to level 0
+//!      of `sums`
+//!   2. PASS_SCAN + level: every workgroup scans a block of the level, for each level that
+//!      `scan::levels` gives for the number of threads
+//!   3. PASS_OFFSETS: every thread writes the offsets of the strings in its range, starting from
+//!      its prefix sum
+//!   4. PASS_GATHER: every thread copies the strings in its range to their offsets
+//!
+//! The total length is at `scan::total_offset` after the scan, so that the values buffer can be
+//! sized before PASS_GATHER.
+use glam::UVec3;
+use kernelcodegen::{atomic, bytes, generate_kernel, scan};
+use spirv_std::{glam, spirv};
+
+// Values of the `pass` uniform
+pub const PASS_COUNT: u32 = 0;
+/// Scans level 0 of `sums`, PASS_SCAN + 1 scans level 1 and so on
+pub const PASS_SCAN: u32 = 1;
+pub const PASS_OFFSETS: u32 = PASS_SCAN + scan::MAX_LEVELS;
+pub const PASS_GATHER: u32 = PASS_OFFSETS + 1;
+
+/// Must match parsecsv::STRING_QUOTED
+const STRING_QUOTED: u32 = 1 << 31;
+
+/// Length of the string of `row` once unquoted
+fn string_len(strings: &[u32], row: usize) -> u32 {
+    strings[row * 2 + 1] & !STRING_QUOTED
+}
+
+/// Copies the `len` bytes of the string that starts at byte `start` of `src` to the bytes of
+/// `values` starting at `out_start`, collapsing doubled quotes if the string was `quoted`. Words
+/// that are shared with the neighbouring strings are written atomically.
+fn copy_string(
+    src: &[u32],
+    start: usize,
+    len: usize,
+    quote: u32,
+    quoted: bool,
+    values: &mut [u32],
+    out_start: usize,
+) {
+    let out_end = out_start + len;
+    let mut i = start;
+    let mut out = out_start;
+    let mut word = 0;
+    while out < out_end {
+        if quoted && bytes::get(src, i) == quote {
+            // The first quote of a doubled quote
+            i += 1;
+        }
+        word |= bytes::get(src, i) << (8 * (out % 4));
+        i += 1;
+        out += 1;
+        if out % 4 == 0 || out == out_end {
+            let w = (out - 1) / 4;
+            if w * 4 >= out_start && out % 4 == 0 {
+                values[w] = word;
+            } else {
+                atomic::or(&mut values[w], word);
+            }
+            word = 0;
+        }
+    }
+}
+
+#[generate_kernel()]
+#[spirv(compute(threads(256)))]
+pub fn main_gatherstrings(
+    #[spirv(local_invocation_id)] lid: UVec3,
+    #[spirv(global_invocation_id)] id: UVec3,
+    // The residual that was bound to parsecsv
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] residual: &[u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] residual_len: &u32,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] input: &[u32],
+    // min(chunk_lines, rows - chunk_lines * id.x) is the number of rows to process per thread
+    #[spirv(uniform, descriptor_set = 0, binding = 3)] chunk_lines: &u32,
+    // The (offset, length) pairs written by parsecsv for a TYPE_STRING column, or by dictencode
+    // for the distinct strings of a dictionary column
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] strings: &[u32],
+    // Offset of every row's string in `values`, followed by the total length. Written by
+    // PASS_OFFSETS.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] offsets: &mut [u32],
+    // Bytes of the strings, packed into words least significant byte first. Must be zeroed by
+    // the caller, and is only used by PASS_GATHER.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] values: &mut [u32],
+    // Quote character the strings were parsed with
+    #[spirv(uniform, descriptor_set = 0, binding = 7)] quote: &u32,
+    // One of the PASS_* constants
+    #[spirv(uniform, descriptor_set = 0, binding = 8)] pass: &u32,
+    // The number of rows in `strings`, in the first word
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 9)] n_strings: &[u32],
+    // The total length of the strings of every thread, followed by the levels of their scan.
+    // Must hold `scan::sums_len(*n_threads)` words.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 10)] sums: &mut [u32],
+    // Number of threads dispatched for every pass but the scan
+    #[spirv(uniform, descriptor_set = 0, binding = 11)] n_threads: &u32,
+    #[spirv(workgroup)] shared: &mut scan::Shared,
+) {
+    let first = (id.x * *chunk_lines) as usize;
+    let rows = n_strings[0] as usize;
+
+    if *pass == PASS_COUNT {
+        let mut acc = 0;
+        for row in first..(first + *chunk_lines as usize) {
+            if row < rows {
+                acc += string_len(strings, row);
+            }
+        }
+        // Every thread has its own entry, so no synchronization is needed
+        sums[id.x as usize] = acc;
+    } else if *pass == PASS_OFFSETS {
+        let mut offset = scan::prefix(sums, *n_threads, id.x);
+        for row in first..(first + *chunk_lines as usize) {
+            if row < rows {
+                offsets[row] = offset;
+                offset += string_len(strings, row);
+                if row + 1 == rows {
+                    offsets[rows] = offset;
+                }
+            }
+        }
+    } else if *pass == PASS_GATHER {
+        for row in first..(first + *chunk_lines as usize) {
+            if row >= rows {
+                break;
+            }
+
+            let start = strings[row * 2] as usize;
+            let quoted = strings[row * 2 + 1] & STRING_QUOTED != 0;
+            let out_start = offsets[row] as usize;
+            let len = offsets[row + 1] as usize - out_start;
+            if start < *residual_len as usize {
+                copy_string(residual, start, len, *quote, quoted, values, out_start);
+            } else {
+                let start = start - *residual_len as usize;
+                copy_string(input, start, len, *quote, quoted, values, out_start);
+            }
+        }
+    } else {
+        scan::scan_block(sums, shared, *n_threads, *pass - PASS_SCAN, id.x, lid.x);
+    }
+}
@@ -1,47 +1,51 @@
 #![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
 #![deny(warnings)]
 
 use glam::UVec3;
-use kernelcodegen::generate_kernel;
-use spirv_std::{arch, glam, memory, spirv};
+use kernelcodegen::{bytes, generate_kernel, scan};
+use spirv_std::{glam, spirv};
 
 #[generate_kernel()]
 #[spirv(compute(threads(256)))]
 pub fn main_getcharpos(
-    #[spirv(local_invocation_id)] lid: UVec3,
     #[spirv(global_invocation_id)] id: UVec3,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &[u8],
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &[u32],
     #[spirv(uniform, descriptor_set = 0, binding = 1)] chunk_size: &u32,
     #[spirv(uniform, descriptor_set = 0, binding = 2)] data_len: &u32,
-    #[spirv(uniform, descriptor_set = 0, binding = 3)] char: &u8,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] n_lines_per_thread: &[u32],
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 5)] write_idx: &mut [u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 3)] char: &u32,
+    // countchar's `sums`, once every level has been scanned
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 4)] sums: &[u32],
+    // Number of threads, which countchar was dispatched with as well
+    #[spirv(uniform, descriptor_set = 0, binding = 5)] n_threads: &u32,
+    // The offset of every line's terminator, in file order
     #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] output: &mut [u32],
+    // See countchar
+    #[spirv(uniform, descriptor_set = 0, binding = 7)] quote: &u32,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 8)] in_quotes: &[u32],
 ) {
     let index = id.x as usize;
-    if index == 0 {
-        output[0] = 0;
-    }
-
-    let lindex = lid.x as usize;
 
-    let out_start = (0..lindex)
-        .into_iter()
-        .fold(0, |acc, i| acc + n_lines_per_thread[i]) as usize;
+    // The lines of the threads before this one come first, so each thread writes its own lines
+    // in order without racing the others
+    let mut out_index = scan::prefix(sums, *n_threads, id.x) as usize;
 
     let start: usize = index * (*chunk_size as usize);
+    let mut quoted = *quote != 0 && (in_quotes[index / 32] >> (index % 32)) & 1 == 1;
     for i in start..(start + *chunk_size as usize) {
-        if i < (*data_len as usize) && input[i] == *char {
-            let out_index = unsafe {
-                arch::atomic_i_sub::<
-                    u32,
-                    { memory::Scope::Device as u32 },
-                    { memory::Semantics::OUTPUT_MEMORY.bits() },
-                >(&mut write_idx[lindex], 1)
-            } - 1;
-            let out_index = out_index as usize;
-
-            output[out_start + out_index + 1] = 1 + i as u32;
+        if i >= (*data_len as usize) {
+            continue;
+        }
+        let c = bytes::get(input, i);
+        if *quote != 0 && c == *quote {
+            quoted = !quoted;
+        } else if c == *char && !quoted {
+            output[out_index] = i as u32;
+            out_index += 1;
         }
     }
 }
@@ -0,0 +1,812 @@
+//! Correctly rounded parsing of decimal floating point numbers, ported from the implementation of
+//! `str::parse::<f64>` in Rust's core library (`core::num::dec2flt`). Numbers are converted by the
+//! Eisel-Lemire algorithm, which always rounds correctly for up to 19 significant digits. Longer
+//! numbers that it can't round fall back to big decimal arithmetic, which is much slower.
+use kernelcodegen::bytes;
+
+use crate::parse_sign;
+use crate::pow5::SMALLEST_POWER_OF_FIVE;
+
+/// Parameters of a binary floating point format
+#[derive(Clone, Copy)]
+pub struct FloatFormat {
+    mantissa_explicit_bits: u32,
+    sign_bit: u32,
+    min_exponent_round_to_even: i64,
+    max_exponent_round_to_even: i64,
+    minimum_exponent: i32,
+    infinite_power: i32,
+    smallest_power_of_ten: i64,
+    largest_power_of_ten: i64,
+}
+
+pub const F32: FloatFormat = FloatFormat {
+    mantissa_explicit_bits: 23,
+    sign_bit: 31,
+    min_exponent_round_to_even: -17,
+    max_exponent_round_to_even: 10,
+    minimum_exponent: -127,
+    infinite_power: 0xff,
+    smallest_power_of_ten: -65,
+    largest_power_of_ten: 38,
+};
+
+pub const F64: FloatFormat = FloatFormat {
+    mantissa_explicit_bits: 52,
+    sign_bit: 63,
+    min_exponent_round_to_even: -4,
+    max_exponent_round_to_even: 23,
+    minimum_exponent: -1023,
+    infinite_power: 0x7ff,
+    smallest_power_of_ten: -342,
+    largest_power_of_ten: 308,
+};
+
+/// The explicit mantissa bits and biased exponent of a float. `power2` is negative if the value
+/// couldn't be rounded.
+#[derive(Clone, Copy)]
+struct BiasedFp {
+    mantissa: u64,
+    power2: i32,
+}
+
+impl BiasedFp {
+    fn zero_pow2(power2: i32) -> Self {
+        BiasedFp {
+            mantissa: 0,
+            power2,
+        }
+    }
+}
+
+fn leading_zeros(w: u64) -> u32 {
+    let hi = (w >> 32) as u32;
+    if hi != 0 {
+        hi.leading_zeros()
+    } else {
+        32 + (w as u32).leading_zeros()
+    }
+}
+
+/// The full 128 bit product of `a` and `b`, as (low, high) words
+fn full_multiplication(a: u64, b: u64) -> (u64, u64) {
+    let (a_lo, a_hi) = (a & 0xffff_ffff, a >> 32);
+    let (b_lo, b_hi) = (b & 0xffff_ffff, b >> 32);
+    let lo_lo = a_lo * b_lo;
+    let hi_lo = a_hi * b_lo;
+    let lo_hi = a_lo * b_hi;
+    let hi_hi = a_hi * b_hi;
+    let cross = (lo_lo >> 32) + (hi_lo & 0xffff_ffff) + lo_hi;
+    let hi = (hi_lo >> 32) + (cross >> 32) + hi_hi;
+    let lo = (cross << 32) | (lo_lo & 0xffff_ffff);
+    (lo, hi)
+}
+
+/// Approximates `w * 5^q` with the `precision` most significant bits exact, as (low, high) words
+fn compute_product_approx(q: i64, w: u64, precision: u32, powers_of_five: &[u64]) -> (u64, u64) {
+    let mask = if precision < 64 {
+        0xffff_ffff_ffff_ffff_u64 >> precision
+    } else {
+        0xffff_ffff_ffff_ffff_u64
+    };
+
+    let index = ((q - SMALLEST_POWER_OF_FIVE) * 2) as usize;
+    let hi5 = powers_of_five[index];
+    let lo5 = powers_of_five[index + 1];
+    let (mut first_lo, mut first_hi) = full_multiplication(w, hi5);
+    if first_hi & mask == mask {
+        // The low word of the power of five is needed to tell whether the bits that matter are
+        // exact. The product is always exact for q < 55, since 5^55 < 2^128.
+        let (_, second_hi) = full_multiplication(w, lo5);
+        first_lo = first_lo.wrapping_add(second_hi);
+        if second_hi > first_lo {
+            first_hi += 1;
+        }
+    }
+    (first_lo, first_hi)
+}
+
+/// floor(log2(10^q)) + 63
+fn power(q: i32) -> i32 {
+    (q.wrapping_mul(152_170 + 65536) >> 16) + 63
+}
+
+/// Eisel-Lemire: rounds `w * 10^q` to the nearest float of `format`
+fn compute_float(q: i64, mut w: u64, format: FloatFormat, powers_of_five: &[u64]) -> BiasedFp {
+    let fp_zero = BiasedFp::zero_pow2(0);
+    let fp_inf = BiasedFp::zero_pow2(format.infinite_power);
+    let fp_error = BiasedFp::zero_pow2(-1);
+    let mantissa_bits = format.mantissa_explicit_bits;
+
+    if w == 0 || q < format.smallest_power_of_ten {
+        return fp_zero;
+    } else if q > format.largest_power_of_ten {
+        return fp_inf;
+    }
+    // Normalize the significant digits, so that the most significant bit is set
+    let lz = leading_zeros(w);
+    w <<= lz;
+    let (lo, hi) = compute_product_approx(q, w, mantissa_bits + 3, powers_of_five);
+    // If the product could be one less than the exact value, adding the 1 back could round up
+    // past the halfway point, which can only be ruled out for exact powers of five.
+    if lo == 0xffff_ffff_ffff_ffff && !(-27..=55).contains(&q) {
+        return fp_error;
+    }
+    let upperbit = (hi >> 63) as i32;
+    let shift = upperbit + 64 - mantissa_bits as i32 - 3;
+    let mut mantissa = hi >> shift;
+    let mut power2 = power(q as i32) + upperbit - lz as i32 - format.minimum_exponent;
+    if power2 <= 0 {
+        if -power2 + 1 >= 64 {
+            // More than 64 bits below the minimum exponent, must be 0
+            return fp_zero;
+        }
+        // A subnormal value
+        mantissa >>= -power2 + 1;
+        mantissa += mantissa & 1;
+        mantissa >>= 1;
+        power2 = (mantissa >= (1_u64 << mantissa_bits)) as i32;
+        return BiasedFp { mantissa, power2 };
+    }
+    // Exactly halfway between two floats, which can only happen when 5^q fits in 64 bits: round
+    // to even rather than up.
+    if lo <= 1
+        && q >= format.min_exponent_round_to_even
+        && q <= format.max_exponent_round_to_even
+        && mantissa & 3 == 1
+        && (mantissa << shift) == hi
+    {
+        mantissa &= !1_u64;
+    }
+    mantissa += mantissa & 1;
+    mantissa >>= 1;
+    if mantissa >= (2_u64 << mantissa_bits) {
+        // Rounding up carried into the next power of two
+        mantissa = 1_u64 << mantissa_bits;
+        power2 += 1;
+    }
+    // Drop the hidden bit
+    mantissa &= !(1_u64 << mantissa_bits);
+    if power2 >= format.infinite_power {
+        return fp_inf;
+    }
+    BiasedFp { mantissa, power2 }
+}
+
+/// Maximum number of digits kept by a `Decimal`. The digits past this can only matter to round
+/// a value exactly halfway between two floats, which `truncated` is enough for.
+const MAX_DIGITS: usize = 768;
+/// Number of digits that always fit in a u64
+const MAX_DIGITS_WITHOUT_OVERFLOW: usize = 19;
+const DECIMAL_POINT_RANGE: i32 = 2047;
+
+/// An arbitrary precision decimal `0.digits * 10^decimal_point`
+struct Decimal {
+    num_digits: usize,
+    decimal_point: i32,
+    /// Whether non-zero digits were dropped past MAX_DIGITS
+    truncated: bool,
+    digits: [u32; MAX_DIGITS],
+}
+
+impl Decimal {
+    fn try_add_digit(&mut self, digit: u32) {
+        if self.num_digits < MAX_DIGITS {
+            self.digits[self.num_digits] = digit;
+        }
+        self.num_digits += 1;
+    }
+
+    fn trim(&mut self) {
+        while self.num_digits != 0 && self.digits[self.num_digits - 1] == 0 {
+            self.num_digits -= 1;
+        }
+    }
+
+    /// The integer part of the decimal, rounded to nearest with ties to even
+    fn round(&self) -> u64 {
+        if self.num_digits == 0 || self.decimal_point < 0 {
+            return 0;
+        } else if self.decimal_point > 18 {
+            return 0xffff_ffff_ffff_ffff_u64;
+        }
+        let dp = self.decimal_point as usize;
+        let mut n = 0_u64;
+        for i in 0..dp {
+            n *= 10;
+            if i < self.num_digits {
+                n += self.digits[i] as u64;
+            }
+        }
+        let mut round_up = false;
+        if dp < self.num_digits {
+            round_up = self.digits[dp] >= 5;
+            if self.digits[dp] == 5 && dp + 1 == self.num_digits {
+                round_up = self.truncated || ((dp != 0) && (1 & self.digits[dp - 1] != 0))
+            }
+        }
+        if round_up {
+            n += 1;
+        }
+        n
+    }
+
+    /// Multiplies the decimal by 2^shift, for shift <= 60
+    fn left_shift(&mut self, shift: usize) {
+        if self.num_digits == 0 {
+            return;
+        }
+        // Every digit is replaced by one digit, and the final carry adds the new leading digits
+        let mut carry = 0_u64;
+        let mut read_index = self.num_digits;
+        while read_index != 0 {
+            read_index -= 1;
+            carry = (carry + ((self.digits[read_index] as u64) << shift)) / 10;
+        }
+        let mut num_new_digits = 0;
+        while carry > 0 {
+            num_new_digits += 1;
+            carry /= 10;
+        }
+
+        let mut read_index = self.num_digits;
+        let mut write_index = self.num_digits + num_new_digits;
+        let mut n = 0_u64;
+        while read_index != 0 {
+            read_index -= 1;
+            write_index -= 1;
+            n += (self.digits[read_index] as u64) << shift;
+            let quotient = n / 10;
+            let remainder = n - (10 * quotient);
+            if write_index < MAX_DIGITS {
+                self.digits[write_index] = remainder as u32;
+            } else if remainder > 0 {
+                self.truncated = true;
+            }
+            n = quotient;
+        }
+        while n > 0 {
+            write_index -= 1;
+            let quotient = n / 10;
+            let remainder = n - (10 * quotient);
+            if write_index < MAX_DIGITS {
+                self.digits[write_index] = remainder as u32;
+            } else if remainder > 0 {
+                self.truncated = true;
+            }
+            n = quotient;
+        }
+        self.num_digits += num_new_digits;
+        if self.num_digits > MAX_DIGITS {
+            self.num_digits = MAX_DIGITS;
+        }
+        self.decimal_point += num_new_digits as i32;
+        self.trim();
+    }
+
+    /// Divides the decimal by 2^shift, for shift <= 60
+    fn right_shift(&mut self, shift: usize) {
+        let mut read_index = 0;
+        let mut write_index = 0;
+        let mut n = 0_u64;
+        while (n >> shift) == 0 {
+            if read_index < self.num_digits {
+                n = (10 * n) + self.digits[read_index] as u64;
+                read_index += 1;
+            } else if n == 0 {
+                return;
+            } else {
+                while (n >> shift) == 0 {
+                    n *= 10;
+                    read_index += 1;
+                }
+                break;
+            }
+        }
+        self.decimal_point -= read_index as i32 - 1;
+        if self.decimal_point < -DECIMAL_POINT_RANGE {
+            self.num_digits = 0;
+            self.decimal_point = 0;
+            self.truncated = false;
+            return;
+        }
+        let mask = (1_u64 << shift) - 1;
+        while read_index < self.num_digits {
+            let new_digit = (n >> shift) as u32;
+            n = (10 * (n & mask)) + self.digits[read_index] as u64;
+            read_index += 1;
+            self.digits[write_index] = new_digit;
+            write_index += 1;
+        }
+        while n > 0 {
+            let new_digit = (n >> shift) as u32;
+            n = 10 * (n & mask);
+            if write_index < MAX_DIGITS {
+                self.digits[write_index] = new_digit;
+                write_index += 1;
+            } else if new_digit > 0 {
+                self.truncated = true;
+            }
+        }
+        self.num_digits = write_index;
+        self.trim();
+    }
+}
+
+/// Reads the digits of `input[start..end]`, which holds digits with at most one `decimal`
+/// separator, times `10^exponent` into a `Decimal`
+fn parse_decimal(input: &[u32], start: usize, end: usize, decimal: u32, exponent: i64) -> Decimal {
+    let mut d = Decimal {
+        num_digits: 0,
+        decimal_point: 0,
+        truncated: false,
+        digits: [0; MAX_DIGITS],
+    };
+    let mut i = start;
+    while i < end && bytes::get(input, i) == b'0' as u32 {
+        i += 1;
+    }
+    while i < end && bytes::get(input, i) != decimal {
+        d.try_add_digit(bytes::get(input, i) - b'0' as u32);
+        i += 1;
+    }
+    if i < end {
+        i += 1;
+        let first = i;
+        if d.num_digits == 0 {
+            while i < end && bytes::get(input, i) == b'0' as u32 {
+                i += 1;
+            }
+        }
+        while i < end {
+            d.try_add_digit(bytes::get(input, i) - b'0' as u32);
+            i += 1;
+        }
+        d.decimal_point = first as i32 - i as i32;
+    }
+    if d.num_digits != 0 {
+        // Trailing zeros were added as digits, but don't need to be
+        let mut n_trailing_zeros = 0;
+        let mut j = end;
+        while j > start {
+            j -= 1;
+            if bytes::get(input, j) == b'0' as u32 {
+                n_trailing_zeros += 1;
+            } else if bytes::get(input, j) != decimal {
+                break;
+            }
+        }
+        d.decimal_point += n_trailing_zeros as i32;
+        d.num_digits -= n_trailing_zeros;
+        d.decimal_point += d.num_digits as i32;
+        if d.num_digits > MAX_DIGITS {
+            d.truncated = true;
+            d.num_digits = MAX_DIGITS;
+        }
+    }
+    d.decimal_point += exponent as i32;
+    for i in d.num_digits..MAX_DIGITS_WITHOUT_OVERFLOW {
+        d.digits[i] = 0;
+    }
+    d
+}
+
+/// Largest shift that `Decimal` can handle at once
+const MAX_SHIFT: usize = 60;
+
+/// floor(log2(10^n)), the largest shift that keeps a decimal with n digits before the point
+/// non-zero, capped at MAX_SHIFT
+fn get_shift(n: usize) -> usize {
+    if n < 19 {
+        (n * 217_706) >> 16
+    } else {
+        MAX_SHIFT
+    }
+}
+
+/// Rounds the decimal number in `input[start..end]` times `10^exponent` to the nearest float of
+/// `format` using big decimal arithmetic
+fn parse_long_mantissa(
+    input: &[u32],
+    start: usize,
+    end: usize,
+    decimal: u32,
+    exponent: i64,
+    format: FloatFormat,
+) -> BiasedFp {
+    let fp_zero = BiasedFp::zero_pow2(0);
+    let fp_inf = BiasedFp::zero_pow2(format.infinite_power);
+    let mantissa_bits = format.mantissa_explicit_bits;
+
+    let mut d = parse_decimal(input, start, end, decimal, exponent);
+    if d.num_digits == 0 || d.decimal_point < -324 {
+        return fp_zero;
+    } else if d.decimal_point >= 310 {
+        return fp_inf;
+    }
+    let mut exp2 = 0_i32;
+    // Shift right toward (1/2 ... 1]
+    while d.decimal_point > 0 {
+        let shift = get_shift(d.decimal_point as usize);
+        d.right_shift(shift);
+        if d.decimal_point < -DECIMAL_POINT_RANGE {
+            return fp_zero;
+        }
+        exp2 += shift as i32;
+    }
+    // Shift left toward (1/2 ... 1]
+    while d.decimal_point <= 0 {
+        let shift = if d.decimal_point == 0 {
+            if d.digits[0] >= 5 {
+                break;
+            } else if d.digits[0] <= 1 {
+                2
+            } else {
+                1
+            }
+        } else {
+            get_shift((-d.decimal_point) as usize)
+        };
+        d.left_shift(shift);
+        if d.decimal_point > DECIMAL_POINT_RANGE {
+            return fp_inf;
+        }
+        exp2 -= shift as i32;
+    }
+    // The decimal is now in [1/2 ... 1], but the binary format uses [1 ... 2]
+    exp2 -= 1;
+    while (format.minimum_exponent + 1) > exp2 {
+        let mut n = ((format.minimum_exponent + 1) - exp2) as usize;
+        if n > MAX_SHIFT {
+            n = MAX_SHIFT;
+        }
+        d.right_shift(n);
+        exp2 += n as i32;
+    }
+    if (exp2 - format.minimum_exponent) >= format.infinite_power {
+        return fp_inf;
+    }
+    // Shift the decimal to the hidden bit, and round to get the mantissa and hidden bit
+    d.left_shift(mantissa_bits as usize + 1);
+    let mut mantissa = d.round();
+    if mantissa >= (1_u64 << (mantissa_bits + 1)) {
+        // Rounding up carried past the hidden bit
+        d.right_shift(1);
+        exp2 += 1;
+        mantissa = d.round();
+        if (exp2 - format.minimum_exponent) >= format.infinite_power {
+            return fp_inf;
+        }
+    }
+    let mut power2 = exp2 - format.minimum_exponent;
+    if mantissa < (1_u64 << mantissa_bits) {
+        power2 -= 1;
+    }
+    mantissa &= (1_u64 << mantissa_bits) - 1;
+    BiasedFp { mantissa, power2 }
+}
+
+/// Whether the byte `b` is an ASCII digit
+fn is_digit(b: u32) -> bool {
+    b >= b'0' as u32 && b <= b'9' as u32
+}
+
+/// Lowercase text of up to 8 bytes, packed least significant byte first so that the kernels
+/// never handle 8-bit values. Only meant for constants, so that the packing happens at compile
+/// time.
+#[derive(Clone, Copy)]
+pub struct Keyword {
+    bytes: u64,
+    len: usize,
+}
+
+impl Keyword {
+    pub const fn new(text: &[u8]) -> Self {
+        let mut bytes = 0;
+        let mut i = 0;
+        while i < text.len() {
+            bytes |= (text[i] as u64) << (8 * i);
+            i += 1;
+        }
+        Keyword {
+            bytes,
+            len: text.len(),
+        }
+    }
+}
+
+const INF: Keyword = Keyword::new(b"inf");
+const INFINITY: Keyword = Keyword::new(b"infinity");
+const NAN: Keyword = Keyword::new(b"nan");
+
+/// Whether `input[start..end]` is `expected`, ignoring case
+pub fn matches_ignore_case(input: &[u32], start: usize, end: usize, expected: Keyword) -> bool {
+    if end - start != expected.len {
+        return false;
+    }
+    for i in 0..expected.len {
+        let c = (expected.bytes >> (8 * i)) as u32 & 0xff;
+        if (bytes::get(input, start + i) | 0x20) != c {
+            return false;
+        }
+    }
+    true
+}
+
+/// Parses `input[start..end]` as a float of `format`, accepting the same syntax as
+/// `str::parse::<f64>` but with `decimal` as the decimal separator: an optional sign followed by
+/// digits with at most one separator and an optional exponent, or `inf`, `infinity` or `nan` in
+/// any case. Returns the bits of the float, and whether the input is a valid float.
+pub fn parse_float(
+    input: &[u32],
+    start: usize,
+    end: usize,
+    decimal: u32,
+    format: FloatFormat,
+    powers_of_five: &[u64],
+) -> (u64, bool) {
+    let mantissa_bits = format.mantissa_explicit_bits;
+    let (mut i, negative) = parse_sign(input, start, end);
+    let sign = (negative as u64) << format.sign_bit;
+
+    let infinity = (format.infinite_power as u64) << mantissa_bits;
+    if matches_ignore_case(input, i, end, INF) || matches_ignore_case(input, i, end, INFINITY) {
+        return (sign | infinity, true);
+    }
+    if matches_ignore_case(input, i, end, NAN) {
+        return (sign | infinity | (1 << (mantissa_bits - 1)), true);
+    }
+
+    // The first 19 significant digits go into `w`, with `exponent` tracking the power of ten
+    let digits_start = i;
+    let mut w: u64 = 0;
+    let mut exponent: i64 = 0;
+    let mut n_digits = 0;
+    let mut n_significant = 0;
+    let mut truncated = false;
+    let mut seen_decimal = false;
+    while i < end {
+        let b = bytes::get(input, i);
+        if b == decimal && !seen_decimal {
+            seen_decimal = true;
+        } else if is_digit(b) {
+            let digit = (b - b'0' as u32) as u64;
+            n_digits += 1;
+            if n_significant < MAX_DIGITS_WITHOUT_OVERFLOW && (n_significant > 0 || digit != 0) {
+                w = w * 10 + digit;
+                n_significant += 1;
+                if seen_decimal {
+                    exponent -= 1;
+                }
+            } else if n_significant == 0 {
+                // A leading zero
+                if seen_decimal {
+                    exponent -= 1;
+                }
+            } else {
+                // A digit past the ones that fit
+                if !seen_decimal {
+                    exponent += 1;
+                }
+                truncated |= digit != 0;
+            }
+        } else {
+            break;
+        }
+        i += 1;
+    }
+    if n_digits == 0 {
+        return (0, false);
+    }
+    let digits_end = i;
+
+    let mut explicit_exponent: i64 = 0;
+    if i < end && (bytes::get(input, i) | 0x20) == b'e' as u32 {
+        let (exponent_start, negative_exponent) = parse_sign(input, i + 1, end);
+        i = exponent_start;
+        while i < end && is_digit(bytes::get(input, i)) {
+            // Anything this large is zero or infinity anyway
+            if explicit_exponent < 0x10000 {
+                explicit_exponent =
+                    explicit_exponent * 10 + (bytes::get(input, i) - b'0' as u32) as i64;
+            }
+            i += 1;
+        }
+        if i == exponent_start {
+            return (0, false);
+        }
+        if negative_exponent {
+            explicit_exponent = -explicit_exponent;
+        }
+    }
+    if i != end {
+        return (0, false);
+    }
+    exponent += explicit_exponent;
+
+    let mut fp = compute_float(exponent, w, format, powers_of_five);
+    if truncated && fp.power2 >= 0 {
+        // The dropped digits put the value somewhere between w and w + 1
+        let fp_up = compute_float(exponent, w + 1, format, powers_of_five);
+        if fp_up.mantissa != fp.mantissa || fp_up.power2 != fp.power2 {
+            fp.power2 = -1;
+        }
+    }
+    if fp.power2 < 0 {
+        fp = parse_long_mantissa(
+            input,
+            digits_start,
+            digits_end,
+            decimal,
+            explicit_exponent,
+            format,
+        );
+    }
+    (
+        sign | fp.mantissa | ((fp.power2 as u64) << mantissa_bits),
+        true,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::POWERS_OF_FIVE;
+
+    fn parse_f64(text: &str, decimal: u8) -> Option<u64> {
+        let input = bytes::pack(text.as_bytes());
+        let decimal = decimal as u32;
+        let (bits, valid) = parse_float(&input, 0, text.len(), decimal, F64, &POWERS_OF_FIVE);
+        valid.then_some(bits)
+    }
+
+    fn parse_f32(text: &str, decimal: u8) -> Option<u32> {
+        let input = bytes::pack(text.as_bytes());
+        let decimal = decimal as u32;
+        let (bits, valid) = parse_float(&input, 0, text.len(), decimal, F32, &POWERS_OF_FIVE);
+        valid.then_some(bits as u32)
+    }
+
+    /// Checks that `text` parses to the same f64 and f32 as `str::parse`, or is rejected by both
+    fn check(text: &str) {
+        let expected = text.parse::<f64>().ok().map(f64::to_bits);
+        assert_eq!(parse_f64(text, b'.'), expected, "f64 {:?}", text);
+        let expected = text.parse::<f32>().ok().map(f32::to_bits);
+        assert_eq!(parse_f32(text, b'.'), expected, "f32 {:?}", text);
+    }
+
+    #[test]
+    fn simple() {
+        for text in [
+            "0", "-0", "+1", "1.5", ".5", "5.", "3.14159", "-2.5e-3", "1E10", "007.25",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for text in [
+            "", "-", ".", "e5", "1e", "1e+", "1.2.3", "1,5", "0x10", "1 ", "infx", "nana",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn ties_round_to_even() {
+        for text in [
+            // 2^53 + 1 and 2^53 + 3 are halfway between two f64s
+            "9007199254740993",
+            "9007199254740995",
+            // 1 + 2^-53, halfway between 1 and the next f64
+            "1.00000000000000011102230246251565404236316680908203125",
+            "1.00000000000000011102230246251565404236316680908203124",
+            "1.00000000000000011102230246251565404236316680908203126",
+            // 2^24 + 1 and 2^24 + 3 are halfway between two f32s
+            "16777217",
+            "16777219",
+            "0.5",
+            "2.5",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn subnormals_and_overflow() {
+        for text in [
+            "4.9406564584124654e-324",
+            "2.4703282292062327e-324",
+            "2.4703282292062328e-324",
+            "2.2250738585072011e-308",
+            "2.2250738585072014e-308",
+            "1.4e-45",
+            "7.0e-46",
+            "1.1754942e-38",
+            "1.7976931348623157e308",
+            "1.7976931348623158e308",
+            "1.7976931348623159e308",
+            "3.4028235e38",
+            "3.4028236e38",
+            "-1e309",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn infinity_and_nan() {
+        for text in [
+            "inf",
+            "-inf",
+            "+inf",
+            "INF",
+            "Infinity",
+            "-infinity",
+            "nan",
+            "NaN",
+            "-nan",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn long_mantissas() {
+        check("123456789012345678901234567890");
+        check("0.1000000000000000055511151231257827021181583404541015625");
+        // Ties that only the digits past the 19th break, so Eisel-Lemire can't round them
+        check("9007199254740993.0000000000000000000001");
+        check("9007199254740992.9999999999999999999999");
+        let halfway = "1.00000000000000011102230246251565404236316680908203125";
+        check(&format!("{}{}1", halfway, "0".repeat(800)));
+        check(&format!("0.{}1e-300", "0".repeat(700)));
+        check(&format!("{}e-330", "9".repeat(600)));
+    }
+
+    #[test]
+    fn large_exponents() {
+        for text in [
+            "1e308",
+            "1e309",
+            "1e-400",
+            "1e99999999999",
+            "1e-99999999999",
+            "0e99999",
+            "0.000001e310",
+            "123456e-330",
+            "1000000000000000000000000e-25",
+        ] {
+            check(text);
+        }
+    }
+
+    #[test]
+    fn comma_decimal_separator() {
+        assert_eq!(parse_f64("2,75", b','), Some(2.75f64.to_bits()));
+        assert_eq!(parse_f64("-1,5e3", b','), Some((-1500f64).to_bits()));
+        assert_eq!(parse_f32("0,1", b','), Some(0.1f32.to_bits()));
+        assert_eq!(parse_f64("2.75", b','), None);
+        assert_eq!(parse_f64("1,2,3", b','), None);
+    }
+
+    /// Round trips pseudo-random floats through their shortest and exponent representations
+    #[test]
+    fn round_trips() {
+        let mut state: u64 = 0x853c49e6748fea9b;
+        for _ in 0..10000 {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            let value = f64::from_bits(state);
+            if value.is_nan() {
+                continue;
+            }
+            check(&value.to_string());
+            check(&format!("{:e}", value));
+            check(&format!("{:.3e}", value));
+            check(&(value as f32).to_string());
+        }
+    }
+}
@@ -1,69 +1,950 @@
 #![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
 use glam::UVec3;
-use kernelcodegen::generate_kernel;
+use kernelcodegen::{atomic, bytes, generate_kernel};
 use spirv_std::{glam, spirv};
 
-fn parse_u32(input: &[u8], start_offset: usize, end_offset: usize) -> u32 {
+mod float;
+mod pow5;
+
+use float::Keyword;
+pub use pow5::POWERS_OF_FIVE;
+
+// Type codes for the `column_type` uniform. Values are written to `parsed` as `type_width` u32
+// words each, least significant word first.
+pub const TYPE_U32: u32 = 0;
+pub const TYPE_I32: u32 = 1;
+pub const TYPE_I64: u32 = 2;
+pub const TYPE_F32: u32 = 3;
+pub const TYPE_F64: u32 = 4;
+/// Written as 0 or 1
+pub const TYPE_BOOL: u32 = 5;
+/// A date laid out as given by the datetime format, `YYYY-MM-DD` by default. Written as the
+/// number of days since 1970-01-01.
+pub const TYPE_DATE: u32 = 6;
+/// Text, written as the offset of the field's first byte followed by its length once unquoted.
+/// The offset counts from the start of the residual, with the input following right after it.
+/// Bit STRING_QUOTED of the length is set if the field was quoted, in which case the bytes
+/// between the quotes hold doubled quotes that gatherstrings collapses.
+pub const TYPE_STRING: u32 = 7;
+pub const STRING_QUOTED: u32 = 1 << 31;
+/// `[+-]digits[.digits]`, written as an i64 holding the value times 10^scale. The code also holds
+/// the precision and scale of the column, see `decimal_type`.
+pub const TYPE_DECIMAL: u32 = 8;
+/// A date and time laid out as given by the datetime format, `YYYY-MM-DD HH:MM:SS[.fff]` by
+/// default. Written as the number of microseconds since 1970-01-01 00:00:00, as an i64.
+pub const TYPE_TIMESTAMP: u32 = 9;
+/// Bits of a type code that select the type, the rest hold its parameters
+pub const TYPE_MASK: u32 = 0xff;
+/// Decimals are written as i64, which holds any number of up to 18 digits
+pub const MAX_DECIMAL_PRECISION: u32 = 18;
+
+/// The type code of a decimal with `precision` digits, `scale` of which follow the decimal
+/// separator. `scale` must be at most `precision`, which must be at most MAX_DECIMAL_PRECISION.
+pub fn decimal_type(precision: u32, scale: u32) -> u32 {
+    TYPE_DECIMAL | (precision << 8) | (scale << 16)
+}
+
+// Items of a datetime format, which lays out each field at a fixed position. Items below 256 are
+// bytes that must appear as they are, and the others are fields of the given number of digits.
+/// 4 digits
+pub const FORMAT_YEAR: u32 = 256;
+/// 2 digits, from 01 to 12
+pub const FORMAT_MONTH: u32 = 257;
+/// 2 digits, from 01 to the number of days in the month
+pub const FORMAT_DAY: u32 = 258;
+/// 2 digits, from 00 to 23
+pub const FORMAT_HOUR: u32 = 259;
+/// 2 digits, from 00 to 59
+pub const FORMAT_MINUTE: u32 = 260;
+/// 2 digits, from 00 to 59
+pub const FORMAT_SECOND: u32 = 261;
+/// An optional `.` followed by 1 to 9 digits of a second, which are truncated to microseconds.
+/// Can only be the last item of a format.
+pub const FORMAT_FRACTION: u32 = 262;
+
+// Error kinds recorded in the `errors` buffer
+pub const STATUS_OK: u32 = 0;
+/// The field is not a valid value of the column's type
+pub const ERROR_INVALID: u32 = 1;
+/// The field is well formed, but its value can't be represented by the column's type
+pub const ERROR_OUT_OF_RANGE: u32 = 2;
+/// The line has fewer fields than the column's index
+pub const ERROR_MISSING_FIELD: u32 = 3;
+/// The field is empty, but the column isn't nullable
+pub const ERROR_EMPTY: u32 = 4;
+/// The field is empty and the column is nullable
+const STATUS_NULL: u32 = 0xff;
+
+/// `errors[0]` holds the number of errors, followed by (row, error kind) pairs for as many
+/// errors as fit in the buffer.
+pub const ERROR_ENTRY_WIDTH: usize = 2;
+
+/// Number of u32 words a value of type `ty` occupies in the output
+pub fn type_width(ty: u32) -> usize {
+    let ty = ty & TYPE_MASK;
+    if ty == TYPE_I64
+        || ty == TYPE_F64
+        || ty == TYPE_STRING
+        || ty == TYPE_DECIMAL
+        || ty == TYPE_TIMESTAMP
+    {
+        2
+    } else {
+        1
+    }
+}
+
+/// Skips the `-` or `+` at `input[i]`, if `i < end` and there is one. Returns the position after
+/// the sign, and whether it was `-`.
+fn parse_sign(input: &[u32], i: usize, end: usize) -> (usize, bool) {
+    if i < end {
+        let c = bytes::get(input, i);
+        if c == b'-' as u32 || c == b'+' as u32 {
+            return (i + 1, c == b'-' as u32);
+        }
+    }
+    (i, false)
+}
+
+fn parse_u32(input: &[u32], start_offset: usize, end_offset: usize) -> (u32, u32) {
     let mut val: u32 = 0;
     for i in start_offset..end_offset {
-        let b = input[i];
+        let b = bytes::get(input, i);
+        if b < b'0' as u32 || b > b'9' as u32 {
+            return (0, ERROR_INVALID);
+        }
+        let digit = b - b'0' as u32;
+        if val > (u32::MAX - digit) / 10 {
+            return (0, ERROR_OUT_OF_RANGE);
+        }
+        val = val * 10 + digit;
+    }
+    (val, STATUS_OK)
+}
+
+fn parse_i64(input: &[u32], start_offset: usize, end_offset: usize) -> (i64, u32) {
+    let (mut i, negative) = parse_sign(input, start_offset, end_offset);
+    if i == end_offset {
+        return (0, ERROR_INVALID);
+    }
+
+    // Accumulate the magnitude as a u64 so that i64::MIN can be represented
+    let limit = if negative {
+        (i64::MAX as u64) + 1
+    } else {
+        i64::MAX as u64
+    };
+    let mut val: u64 = 0;
+    let mut status = STATUS_OK;
+    while i < end_offset {
+        let b = bytes::get(input, i);
+        if b < b'0' as u32 || b > b'9' as u32 {
+            return (0, ERROR_INVALID);
+        }
+        let digit = (b - b'0' as u32) as u64;
+        if val > (limit - digit) / 10 {
+            // Keep going, an invalid digit later on takes precedence
+            status = ERROR_OUT_OF_RANGE;
+        } else {
+            val = val * 10 + digit;
+        }
+        i += 1;
+    }
+    if status != STATUS_OK {
+        return (0, status);
+    }
+
+    if negative {
+        ((val as i64).wrapping_neg(), STATUS_OK)
+    } else {
+        (val as i64, STATUS_OK)
+    }
+}
+
+fn parse_i32(input: &[u32], start_offset: usize, end_offset: usize) -> (i32, u32) {
+    let (val, status) = parse_i64(input, start_offset, end_offset);
+    if status != STATUS_OK {
+        return (0, status);
+    }
+    if val < i32::MIN as i64 || val > i32::MAX as i64 {
+        return (0, ERROR_OUT_OF_RANGE);
+    }
+    (val as i32, STATUS_OK)
+}
+
+/// Parses a decimal of the type `decimal_type(precision, scale)`, which has `decimal` as its
+/// separator. Fraction digits past `scale` are only accepted if they're zeros, since dropping
+/// any others would lose exactness.
+fn parse_decimal(
+    input: &[u32],
+    start_offset: usize,
+    end_offset: usize,
+    decimal: u32,
+    precision: u32,
+    scale: u32,
+) -> (i64, u32) {
+    let (mut i, negative) = parse_sign(input, start_offset, end_offset);
+
+    // Every value of the column is below 10^precision once scaled, which is at most 10^18 so
+    // val * 10 + 9 can't overflow
+    let mut limit: u64 = 1;
+    for _ in 0..precision {
+        limit *= 10;
+    }
+    let mut val: u64 = 0;
+    let mut n_digits = 0;
+    let mut n_fraction_digits = 0;
+    let mut seen_decimal = false;
+    let mut status = STATUS_OK;
+    while i < end_offset {
+        let b = bytes::get(input, i);
+        i += 1;
+        if b == decimal && !seen_decimal {
+            seen_decimal = true;
+            continue;
+        }
+        if b < b'0' as u32 || b > b'9' as u32 {
+            return (0, ERROR_INVALID);
+        }
+        let digit = (b - b'0' as u32) as u64;
+        n_digits += 1;
+        if seen_decimal && n_fraction_digits == scale {
+            if digit != 0 {
+                // Keep going, an invalid digit later on takes precedence
+                status = ERROR_OUT_OF_RANGE;
+            }
+            continue;
+        }
+        if seen_decimal {
+            n_fraction_digits += 1;
+        }
+        val = val * 10 + digit;
+        if val >= limit {
+            status = ERROR_OUT_OF_RANGE;
+            val = 0;
+        }
+    }
+    if n_digits == 0 {
+        return (0, ERROR_INVALID);
+    }
+    if status != STATUS_OK {
+        return (0, status);
+    }
+
+    while n_fraction_digits < scale {
         val *= 10;
-        if b < b'0' || b > b'9' {
-            return u32::max_value();
+        if val >= limit {
+            return (0, ERROR_OUT_OF_RANGE);
         }
-        val += (b - b'0') as u32;
+        n_fraction_digits += 1;
+    }
+    if negative {
+        (-(val as i64), STATUS_OK)
+    } else {
+        (val as i64, STATUS_OK)
+    }
+}
+
+const TRUE: Keyword = Keyword::new(b"true");
+const FALSE: Keyword = Keyword::new(b"false");
+
+fn parse_bool(input: &[u32], start_offset: usize, end_offset: usize) -> (bool, u32) {
+    // Compare case-insensitively against "true"/"false", or their first letter
+    let c = bytes::get(input, start_offset);
+    let (expected, val) = if c | 0x20 == b't' as u32 || c == b'1' as u32 {
+        (TRUE, true)
+    } else if c | 0x20 == b'f' as u32 || c == b'0' as u32 {
+        (FALSE, false)
+    } else {
+        return (false, ERROR_INVALID);
     };
-    val
+    if end_offset - start_offset == 1
+        || float::matches_ignore_case(input, start_offset, end_offset, expected)
+    {
+        (val, STATUS_OK)
+    } else {
+        (false, ERROR_INVALID)
+    }
+}
+
+fn parse_digits(input: &[u32], start_offset: usize, n_digits: usize) -> (i32, bool) {
+    let mut val = 0;
+    for i in start_offset..(start_offset + n_digits) {
+        let b = bytes::get(input, i);
+        if b < b'0' as u32 || b > b'9' as u32 {
+            return (0, false);
+        }
+        val = val * 10 + (b - b'0' as u32) as i32;
+    }
+    (val, true)
+}
+
+/// Number of days from 1970-01-01 to the given (proleptic Gregorian) date
+fn days_from_civil(year: i32, month: i32, day: i32) -> i32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn days_in_month(year: i32, month: i32) -> i32 {
+    if month == 2 {
+        let leap = (year % 4 == 0 && year % 100 != 0) || year % 400 == 0;
+        if leap {
+            29
+        } else {
+            28
+        }
+    } else if month == 4 || month == 6 || month == 9 || month == 11 {
+        30
+    } else {
+        31
+    }
+}
+
+/// Parses a date or timestamp laid out as given by the FORMAT_* items of `format`. Returns the
+/// number of days since 1970-01-01, and the number of microseconds since the start of the day.
+/// Fields missing from the format are taken from 1970-01-01 00:00:00.
+fn parse_datetime(
+    input: &[u32],
+    start_offset: usize,
+    end_offset: usize,
+    format: &[u32],
+) -> (i32, i64, u32) {
+    let mut fields = [1970, 1, 1, 0, 0, 0];
+    let mut micros: i64 = 0;
+    let mut i = start_offset;
+    for k in 0..format.len() {
+        let item = format[k];
+        if item < 256 {
+            if i == end_offset || bytes::get(input, i) != item {
+                return (0, 0, ERROR_INVALID);
+            }
+            i += 1;
+        } else if item == FORMAT_FRACTION {
+            if i < end_offset && bytes::get(input, i) == b'.' as u32 {
+                i += 1;
+                let mut n_digits = 0;
+                while i < end_offset && n_digits < 9 {
+                    let b = bytes::get(input, i);
+                    if b < b'0' as u32 || b > b'9' as u32 {
+                        return (0, 0, ERROR_INVALID);
+                    }
+                    if n_digits < 6 {
+                        micros = micros * 10 + (b - b'0' as u32) as i64;
+                    }
+                    n_digits += 1;
+                    i += 1;
+                }
+                if n_digits == 0 {
+                    return (0, 0, ERROR_INVALID);
+                }
+                while n_digits < 6 {
+                    micros *= 10;
+                    n_digits += 1;
+                }
+            }
+        } else {
+            let width = if item == FORMAT_YEAR { 4 } else { 2 };
+            if i + width > end_offset {
+                return (0, 0, ERROR_INVALID);
+            }
+            let (val, ok) = parse_digits(input, i, width);
+            if !ok {
+                return (0, 0, ERROR_INVALID);
+            }
+            fields[(item - FORMAT_YEAR) as usize] = val;
+            i += width;
+        }
+    }
+    if i != end_offset {
+        return (0, 0, ERROR_INVALID);
+    }
+
+    let [year, month, day, hour, minute, second] = fields;
+    if month < 1
+        || month > 12
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return (0, 0, ERROR_OUT_OF_RANGE);
+    }
+    let seconds = ((hour * 60 + minute) * 60 + second) as i64;
+    (
+        days_from_civil(year, month, day),
+        seconds * 1_000_000 + micros,
+        STATUS_OK,
+    )
+}
+
+/// Parses `input[start_offset..end_offset]` as a `ty` and returns the words to write to the
+/// output, along with a status. The words are 0 if the field couldn't be parsed. Floats and
+/// decimals use `decimal` as their decimal separator, and floats need `powers_of_five` to be
+/// POWERS_OF_FIVE. Dates and timestamps are laid out as given by the FORMAT_* items of `format`.
+fn parse_value(
+    input: &[u32],
+    start_offset: usize,
+    end_offset: usize,
+    ty: u32,
+    decimal: u32,
+    powers_of_five: &[u64],
+    format: &[u32],
+) -> (u32, u32, u32) {
+    if ty == TYPE_I32 {
+        let (val, status) = parse_i32(input, start_offset, end_offset);
+        (val as u32, 0, status)
+    } else if ty == TYPE_DATE || ty == TYPE_TIMESTAMP {
+        let (days, micros, status) = parse_datetime(input, start_offset, end_offset, format);
+        if ty == TYPE_DATE {
+            (days as u32, 0, status)
+        } else {
+            let val = days as i64 * 86_400_000_000 + micros;
+            (val as u32, (val >> 32) as u32, status)
+        }
+    } else if ty == TYPE_I64 {
+        let (val, status) = parse_i64(input, start_offset, end_offset);
+        (val as u32, (val >> 32) as u32, status)
+    } else if ty == TYPE_F32 || ty == TYPE_F64 {
+        // Parsed straight to the column's precision, since rounding to f64 first and then to f32
+        // can round differently
+        let float_format = if ty == TYPE_F32 {
+            float::F32
+        } else {
+            float::F64
+        };
+        let (bits, valid) = float::parse_float(
+            input,
+            start_offset,
+            end_offset,
+            decimal,
+            float_format,
+            powers_of_five,
+        );
+        if !valid {
+            return (0, 0, ERROR_INVALID);
+        }
+        (bits as u32, (bits >> 32) as u32, STATUS_OK)
+    } else if ty & TYPE_MASK == TYPE_DECIMAL {
+        let precision = (ty >> 8) & 0xff;
+        let scale = ty >> 16;
+        let (val, status) =
+            parse_decimal(input, start_offset, end_offset, decimal, precision, scale);
+        (val as u32, (val >> 32) as u32, status)
+    } else if ty == TYPE_BOOL {
+        let (val, status) = parse_bool(input, start_offset, end_offset);
+        (val as u32, 0, status)
+    } else {
+        let (val, status) = parse_u32(input, start_offset, end_offset);
+        (val, 0, status)
+    }
+}
+
+/// Locates the string `line[start_offset..end_offset]`, collapsing the doubled quotes inside of
+/// it if it was `quoted`.
+fn parse_string(
+    line: &[u32],
+    start_offset: usize,
+    end_offset: usize,
+    quote: u32,
+    quoted: bool,
+) -> (u32, u32, u32) {
+    if !quoted {
+        return (
+            start_offset as u32,
+            (end_offset - start_offset) as u32,
+            STATUS_OK,
+        );
+    }
+    let mut len = 0;
+    let mut i = start_offset;
+    while i < end_offset {
+        if bytes::get(line, i) == quote {
+            // Only a doubled quote can appear inside of a quoted field
+            i += 1;
+        }
+        len += 1;
+        i += 1;
+    }
+    (start_offset as u32, len | STRING_QUOTED, STATUS_OK)
+}
+
+/// Parses field `column` of the line `line[line_start..line_end]` as a `ty`. If `quote` is
+/// non-zero, delimiters between quotes are part of the field, and the quotes around a quoted
+/// field are stripped. Returns the words to write to the output along with a status, which is
+/// STATUS_OK if the field was parsed. See `parse_value` for `decimal`, `powers_of_five` and
+/// `format`.
+pub fn parse_field(
+    line: &[u32],
+    line_start: usize,
+    line_end: usize,
+    delimiter: u32,
+    quote: u32,
+    column: u32,
+    ty: u32,
+    nullable: bool,
+    decimal: u32,
+    powers_of_five: &[u64],
+    format: &[u32],
+) -> (u32, u32, u32) {
+    // Skip over the first `column` fields of the line
+    let mut field_start = line_start;
+    let mut field = 0;
+    let mut quoted = false;
+    let mut i = line_start;
+    while i < line_end {
+        if quote != 0 && bytes::get(line, i) == quote {
+            quoted = !quoted;
+        } else if bytes::get(line, i) == delimiter && !quoted {
+            if field == column {
+                break;
+            }
+            field += 1;
+            field_start = i + 1;
+        }
+        i += 1;
+    }
+
+    if field != column {
+        return (0, 0, ERROR_MISSING_FIELD);
+    }
+    let mut field_end = i;
+    let mut quoted = false;
+    if quote != 0 && field_start < field_end && bytes::get(line, field_start) == quote {
+        if field_end - field_start < 2 || bytes::get(line, field_end - 1) != quote {
+            return (0, 0, ERROR_INVALID);
+        }
+        field_start += 1;
+        field_end -= 1;
+        quoted = true;
+    }
+    if ty == TYPE_STRING {
+        // A quoted empty field is an empty string rather than a null
+        if field_start == field_end && !quoted && nullable {
+            return (0, 0, STATUS_NULL);
+        }
+        return parse_string(line, field_start, field_end, quote, quoted);
+    }
+    if field_start == field_end {
+        return (0, 0, if nullable { STATUS_NULL } else { ERROR_EMPTY });
+    }
+    parse_value(
+        line,
+        field_start,
+        field_end,
+        ty,
+        decimal,
+        powers_of_five,
+        format,
+    )
+}
+
+/// End of the fields of the line `line[line_start..line_end]`, which excludes a trailing `\r` if
+/// `strip_cr` is set
+fn line_content_end(line: &[u32], line_start: usize, line_end: usize, strip_cr: bool) -> usize {
+    if strip_cr && line_end > line_start && bytes::get(line, line_end - 1) == b'\r' as u32 {
+        line_end - 1
+    } else {
+        line_end
+    }
 }
 
 #[generate_kernel()]
 #[spirv(compute(threads(256)))]
 pub fn main_cc(
     #[spirv(global_invocation_id)] id: UVec3,
-    // residual is the buffer from the previous iteration - it's possible that there's some line at the
-    // end of the buffer that is incomplete (i.e. the first line is =
-    //  residual[residual_offset:] + input[:line_start_offsets[0]])
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] _residual: &mut [u8],
-    #[spirv(uniform, descriptor_set = 0, binding = 1)] _residual_len: &u32,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] input: &mut [u8],
+    // The first line of the chunk, including its terminator. The line may have started in the
+    // previous chunk, in which case the driver has stitched the end of the previous chunk onto
+    // input[..=line_end_offsets[0]].
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] residual: &[u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] residual_len: &u32,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 2)] input: &[u32],
     #[spirv(uniform, descriptor_set = 0, binding = 3)] input_len: &u32,
-    #[spirv(uniform, descriptor_set = 0, binding = 4)] delimiter: &u8,
-    // min(chunk_lines, line_start_offsets.len() - chunk_lines * id.x) is the number of lines to
+    #[spirv(uniform, descriptor_set = 0, binding = 4)] delimiter: &u32,
+    // min(chunk_lines, line_end_offsets.len() - chunk_lines * id.x) is the number of lines to
     // process per thread
     #[spirv(uniform, descriptor_set = 0, binding = 5)] chunk_lines: &u32,
-    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] line_start_offsets: &mut [u32],
+    // Offset of the terminator of every line in the chunk, in file order as written by getcharpos
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] line_end_offsets: &mut [u32],
+    // type_width(column_type) words per line
     #[spirv(storage_buffer, descriptor_set = 0, binding = 7)] parsed: &mut [u32],
+    // Index of the field to parse out of each line
+    #[spirv(uniform, descriptor_set = 0, binding = 8)] column: &u32,
+    // One of the TYPE_* constants, or a code from decimal_type
+    #[spirv(uniform, descriptor_set = 0, binding = 9)] column_type: &u32,
+    // Non-zero if empty fields should be treated as nulls rather than errors
+    #[spirv(uniform, descriptor_set = 0, binding = 10)] nullable: &u32,
+    // Bit `row` is set if the row was parsed successfully. Must be zeroed by the caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 11)] validity: &mut [u32],
+    // See ERROR_ENTRY_WIDTH. Must be zeroed by the caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 12)] errors: &mut [u32],
+    // Quote character, or 0 if fields can't be quoted
+    #[spirv(uniform, descriptor_set = 0, binding = 13)] quote: &u32,
+    // Non-zero if a `\r` right before the terminator should be dropped from the line
+    #[spirv(uniform, descriptor_set = 0, binding = 14)] strip_cr: &u32,
+    // Decimal separator of floats
+    #[spirv(uniform, descriptor_set = 0, binding = 15)] decimal: &u32,
+    // Must hold POWERS_OF_FIVE
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 16)] powers_of_five: &[u64],
+    // FORMAT_* items laying out the column's dates or timestamps
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 17)] format: &[u32],
 ) {
+    let width = type_width(*column_type);
     let index = (id.x * *chunk_lines) as usize;
     for i in 0..(*chunk_lines as usize) {
-        if (index + i) >= (line_start_offsets.len() - 1) {
+        let row = index + i;
+        if row >= line_end_offsets.len() {
             break;
         }
 
-        let start_offset = line_start_offsets[index + i] as usize;
-        let res = if start_offset == 0 {
-            // TODO access fields from residual
-            u32::max_value()
+        let end_offset = line_end_offsets[row] as usize;
+        if end_offset >= (*input_len as usize) {
+            continue;
+        }
+
+        // The line starts right after the terminator of the line before it. The first line has
+        // no terminator before it in the chunk, so it's read from the residual instead.
+        let start_offset = match row {
+            0 => 0,
+            _ => line_end_offsets[row - 1] as usize + 1,
+        };
+
+        let nullable = *nullable != 0;
+        let strip_cr = *strip_cr != 0;
+        let (mut lo, hi, status) = if start_offset == 0 {
+            let residual_end = (*residual_len as usize) - 1;
+            let residual_end = line_content_end(residual, 0, residual_end, strip_cr);
+            parse_field(
+                residual,
+                0,
+                residual_end,
+                *delimiter,
+                *quote,
+                *column,
+                *column_type,
+                nullable,
+                *decimal,
+                powers_of_five,
+                format,
+            )
         } else {
-            // start_offset as u32
-            let mut end_offset = start_offset;
-            let mut found = true;
-            let mut s = 0;
-            while input[end_offset] != *delimiter && input[end_offset] != b'\n' {
-                end_offset += 1;
-                s += 1;
-                if end_offset >= (*input_len as usize) {
-                    found = false;
-                    break;
-                }
-            }
+            let end_offset = line_content_end(input, start_offset, end_offset, strip_cr);
+            parse_field(
+                input,
+                start_offset,
+                end_offset,
+                *delimiter,
+                *quote,
+                *column,
+                *column_type,
+                nullable,
+                *decimal,
+                powers_of_five,
+                format,
+            )
+        };
 
-            if found {
-                parse_u32(input, start_offset, end_offset)
-            } else {
-                1111000000 + (start_offset as u32)
+        if *column_type == TYPE_STRING && start_offset != 0 && status == STATUS_OK {
+            lo += *residual_len;
+        }
+        parsed[row * width] = lo;
+        if width == 2 {
+            parsed[row * width + 1] = hi;
+        }
+        if status == STATUS_OK {
+            atomic::or(&mut validity[row / 32], 1 << (row % 32));
+        } else if status != STATUS_NULL {
+            let error_index = atomic::add(&mut errors[0], 1) as usize;
+            let entry = 1 + error_index * ERROR_ENTRY_WIDTH;
+            if entry + ERROR_ENTRY_WIDTH <= errors.len() {
+                errors[entry] = row as u32;
+                errors[entry + 1] = status;
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE: [u32; 5] = [
+        FORMAT_YEAR,
+        b'-' as u32,
+        FORMAT_MONTH,
+        b'-' as u32,
+        FORMAT_DAY,
+    ];
+    const TIMESTAMP: [u32; 12] = [
+        FORMAT_YEAR,
+        b'-' as u32,
+        FORMAT_MONTH,
+        b'-' as u32,
+        FORMAT_DAY,
+        b' ' as u32,
+        FORMAT_HOUR,
+        b':' as u32,
+        FORMAT_MINUTE,
+        b':' as u32,
+        FORMAT_SECOND,
+        FORMAT_FRACTION,
+    ];
+
+    /// Parses `field` as a `ty`, returning both of its words or the error status
+    fn parse(field: &str, ty: u32, format: &[u32]) -> Result<u64, u32> {
+        let words = bytes::pack(field.as_bytes());
+        let decimal = b'.' as u32;
+        match parse_value(&words, 0, field.len(), ty, decimal, &POWERS_OF_FIVE, format) {
+            (lo, hi, STATUS_OK) => Ok((hi as u64) << 32 | lo as u64),
+            (_, _, status) => Err(status),
+        }
+    }
+
+    /// Parses the second field of `line`, which is delimited by `;` if `decimal` is `,`
+    fn parse_second(line: &str, ty: u32, decimal: u8) -> Result<u64, u32> {
+        let delimiter = if decimal == b',' { b';' } else { b',' };
+        let words = bytes::pack(line.as_bytes());
+        let quote = b'"';
+        let powers_of_five = &POWERS_OF_FIVE;
+        match parse_field(
+            &words,
+            0,
+            line.len(),
+            delimiter as u32,
+            quote as u32,
+            1,
+            ty,
+            false,
+            decimal as u32,
+            powers_of_five,
+            &[],
+        ) {
+            (lo, hi, STATUS_OK) => Ok((hi as u64) << 32 | lo as u64),
+            (_, _, status) => Err(status),
+        }
+    }
+
+    fn date(field: &str, format: &[u32]) -> Result<i32, u32> {
+        parse(field, TYPE_DATE, format).map(|val| val as i32)
+    }
+
+    fn timestamp(field: &str) -> Result<i64, u32> {
+        parse(field, TYPE_TIMESTAMP, &TIMESTAMP).map(|val| val as i64)
+    }
+
+    fn decimal(field: &str, precision: u32, scale: u32) -> Result<i64, u32> {
+        let ty = decimal_type(precision, scale);
+        parse_second(&format!("x,{}", field), ty, b'.').map(|val| val as i64)
+    }
+
+    #[test]
+    fn parses_integers() {
+        assert_eq!(parse("42", TYPE_U32, &[]), Ok(42));
+        assert_eq!(parse("4294967296", TYPE_U32, &[]), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(parse("-42", TYPE_I32, &[]), Ok(-42i32 as u32 as u64));
+        assert_eq!(parse("+2147483647", TYPE_I32, &[]), Ok(i32::MAX as u64));
+        assert_eq!(parse("2147483648", TYPE_I32, &[]), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(
+            parse("-9223372036854775808", TYPE_I64, &[]),
+            Ok(i64::MIN as u64)
+        );
+        assert_eq!(
+            parse("9223372036854775808", TYPE_I64, &[]),
+            Err(ERROR_OUT_OF_RANGE)
+        );
+        assert_eq!(parse("1x", TYPE_I64, &[]), Err(ERROR_INVALID));
+        assert_eq!(parse("-", TYPE_I32, &[]), Err(ERROR_INVALID));
+    }
+
+    #[test]
+    fn parses_bools() {
+        for field in ["true", "TRUE", "True", "t", "T", "1"] {
+            assert_eq!(parse(field, TYPE_BOOL, &[]), Ok(1), "{:?}", field);
+        }
+        for field in ["false", "FALSE", "f", "F", "0"] {
+            assert_eq!(parse(field, TYPE_BOOL, &[]), Ok(0), "{:?}", field);
+        }
+        for field in ["yes", "tru", "falsey", "2", "10", "-1", "t "] {
+            assert_eq!(
+                parse(field, TYPE_BOOL, &[]),
+                Err(ERROR_INVALID),
+                "{:?}",
+                field
+            );
+        }
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(date("1970-01-01", &DATE), Ok(0));
+        assert_eq!(date("1969-12-31", &DATE), Ok(-1));
+        assert_eq!(date("2000-03-01", &DATE), Ok(11017));
+        assert_eq!(date("2024-02-29", &DATE), Ok(19782));
+        assert_eq!(date("1900-03-01", &DATE), Ok(-25508));
+        assert_eq!(date("1600-02-29", &DATE), Ok(-135081));
+        assert_eq!(date("0001-01-01", &DATE), Ok(-719162));
+        assert_eq!(date("9999-12-31", &DATE), Ok(2932896));
+        // %d/%m/%Y
+        let format = [
+            FORMAT_DAY,
+            b'/' as u32,
+            FORMAT_MONTH,
+            b'/' as u32,
+            FORMAT_YEAR,
+        ];
+        assert_eq!(date("29/02/2024", &format), Ok(19782));
+        assert_eq!(date("2024-02-29", &format), Err(ERROR_INVALID));
+    }
+
+    #[test]
+    fn rejects_invalid_dates() {
+        let out_of_range = [
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-31",
+            "2024-01-32",
+            "2024-13-01",
+            "2024-00-10",
+        ];
+        for field in out_of_range {
+            assert_eq!(date(field, &DATE), Err(ERROR_OUT_OF_RANGE), "{:?}", field);
+        }
+        for field in [
+            "2024-1-01",
+            "2024-01-01x",
+            "2024/01/01",
+            "24-01-01",
+            "2024-01-0a",
+        ] {
+            assert_eq!(date(field, &DATE), Err(ERROR_INVALID), "{:?}", field);
+        }
+    }
+
+    #[test]
+    fn parses_decimals() {
+        assert_eq!(decimal("12.34", 10, 2), Ok(1234));
+        assert_eq!(decimal("-0.5", 5, 2), Ok(-50));
+        assert_eq!(decimal("+7", 5, 2), Ok(700));
+        assert_eq!(decimal(".25", 5, 2), Ok(25));
+        assert_eq!(decimal("3.", 5, 2), Ok(300));
+        assert_eq!(decimal("999.99", 5, 2), Ok(99999));
+        assert_eq!(decimal("-42", 2, 0), Ok(-42));
+        assert_eq!(
+            decimal("999999999999999999", 18, 0),
+            Ok(999_999_999_999_999_999)
+        );
+        assert_eq!(decimal("-0.000000000000000001", 18, 18), Ok(-1));
+    }
+
+    #[test]
+    fn keeps_decimals_exact() {
+        // Trailing zeros past the scale don't change the value, but any other digit would be lost
+        assert_eq!(decimal("1.2300", 5, 2), Ok(123));
+        assert_eq!(decimal("1.235", 5, 2), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(decimal("1000", 5, 2), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(decimal("100", 2, 0), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(
+            decimal("1000000000000000000", 18, 0),
+            Err(ERROR_OUT_OF_RANGE)
+        );
+        // An invalid character takes precedence over a value that's out of range
+        assert_eq!(decimal("1.235x", 5, 2), Err(ERROR_INVALID));
+    }
+
+    #[test]
+    fn rejects_invalid_decimals() {
+        for field in [".", "-", "1.2.3", "1e5", "1;5", " 1", "0x1"] {
+            assert_eq!(decimal(field, 10, 2), Err(ERROR_INVALID), "{:?}", field);
+        }
+        assert_eq!(decimal("", 10, 2), Err(ERROR_EMPTY));
+    }
+
+    #[test]
+    fn parses_decimals_with_comma_separators() {
+        let ty = decimal_type(6, 3);
+        assert_eq!(parse_second("x;3,14", ty, b','), Ok(3140));
+        assert_eq!(parse_second("x;\"-0,5\"", ty, b','), Ok(-500i64 as u64));
+        assert_eq!(parse_second("x;3.14", ty, b','), Err(ERROR_INVALID));
+    }
+
+    #[test]
+    fn finds_fields() {
+        let line = "\"a,b\",\"1\"\"\",7";
+        let field = |column, ty, nullable| {
+            let words = bytes::pack(line.as_bytes());
+            parse_field(
+                &words,
+                0,
+                line.len(),
+                b',' as u32,
+                b'"' as u32,
+                column,
+                ty,
+                nullable,
+                b'.' as u32,
+                &[],
+                &[],
+            )
+        };
+        assert_eq!(field(2, TYPE_U32, false), (7, 0, STATUS_OK));
+        assert_eq!(field(3, TYPE_U32, false), (0, 0, ERROR_MISSING_FIELD));
+        // Strings are located by their offset and length, with the quotes around them dropped
+        assert_eq!(
+            field(0, TYPE_STRING, false),
+            (1, 3 | STRING_QUOTED, STATUS_OK)
+        );
+        assert_eq!(
+            field(1, TYPE_STRING, false),
+            (7, 2 | STRING_QUOTED, STATUS_OK)
+        );
+        assert_eq!(field(1, TYPE_U32, false), (0, 0, ERROR_INVALID));
+        let line = bytes::pack(b"1,");
+        let (delimiter, decimal) = (b',' as u32, b'.' as u32);
+        let empty = |ty, nullable| {
+            parse_field(
+                &line,
+                0,
+                2,
+                delimiter,
+                0,
+                1,
+                ty,
+                nullable,
+                decimal,
+                &[],
+                &[],
+            )
         };
-        parsed[index + i] = res;
+        assert_eq!(empty(TYPE_U32, false), (0, 0, ERROR_EMPTY));
+        assert_eq!(empty(TYPE_U32, true), (0, 0, STATUS_NULL));
+        assert_eq!(empty(TYPE_STRING, false), (2, 0, STATUS_OK));
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(timestamp("1970-01-01 00:00:00"), Ok(0));
+        assert_eq!(timestamp("1970-01-01 00:00:01.5"), Ok(1_500_000));
+        assert_eq!(timestamp("2024-02-29 23:59:59"), Ok(1_709_251_199_000_000));
+        assert_eq!(timestamp("1969-12-31 23:59:59.999999"), Ok(-1));
+        // Digits past microseconds are truncated
+        assert_eq!(timestamp("1970-01-01 00:00:00.123456789"), Ok(123_456));
+        assert_eq!(timestamp("1970-01-01 00:00:00."), Err(ERROR_INVALID));
+        assert_eq!(
+            timestamp("1970-01-01 00:00:00.1234567890"),
+            Err(ERROR_INVALID)
+        );
+        assert_eq!(timestamp("1970-01-01 24:00:00"), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(timestamp("1970-01-01 00:60:00"), Err(ERROR_OUT_OF_RANGE));
+        assert_eq!(timestamp("1970-01-01"), Err(ERROR_INVALID));
     }
 }
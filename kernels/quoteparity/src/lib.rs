@@ -0,0 +1,74 @@
+#![cfg_attr(target_arch = "spirv", no_std)]
+#![allow(
+    clippy::too_many_arguments,
+    clippy::needless_range_loop,
+    clippy::manual_range_contains
+)]
+//! Works out whether each thread's range of the input starts inside a quoted field, so that
+//! countchar and getcharpos can skip over terminators that are part of a field's value.
+//!
+//! The state at the start of a range is the parity of the number of quotes before it, which is
+//! the parity of an exclusive prefix sum of the parities of the ranges, see `kernelcodegen::scan`:
+//!   1. PASS_COUNT: every thread writes the parity of the quotes in its range to level 0 of `sums`
+//!   2. PASS_SCAN + level: every workgroup scans a block of the level, for each level that
+//!      `scan::levels` gives for the number of threads
+//!   3. PASS_RESOLVE: every thread adds up its prefix sum and the initial state
+//!
+//! An escaped quote (`""`) toggles the state twice, so it needs no special handling.
+use glam::UVec3;
+use kernelcodegen::{atomic, bytes, generate_kernel, scan};
+use spirv_std::{glam, spirv};
+
+// Values of the `pass` uniform
+pub const PASS_COUNT: u32 = 0;
+/// Scans level 0 of `sums`, PASS_SCAN + 1 scans level 1 and so on
+pub const PASS_SCAN: u32 = 1;
+pub const PASS_RESOLVE: u32 = PASS_SCAN + scan::MAX_LEVELS;
+
+#[generate_kernel()]
+#[spirv(compute(threads(256)))]
+pub fn main_quoteparity(
+    #[spirv(local_invocation_id)] lid: UVec3,
+    #[spirv(global_invocation_id)] id: UVec3,
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 0)] input: &[u32],
+    #[spirv(uniform, descriptor_set = 0, binding = 1)] chunk_size: &u32,
+    #[spirv(uniform, descriptor_set = 0, binding = 2)] data_len: &u32,
+    #[spirv(uniform, descriptor_set = 0, binding = 3)] quote: &u32,
+    // One of the PASS_* constants
+    #[spirv(uniform, descriptor_set = 0, binding = 4)] pass: &u32,
+    // Non-zero if the input starts inside a quoted field
+    #[spirv(uniform, descriptor_set = 0, binding = 5)] initial_state: &u32,
+    // The parity of the quotes of every thread, followed by the levels of their scan. Must hold
+    // `scan::sums_len(*n_threads)` words.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 6)] sums: &mut [u32],
+    // Number of threads dispatched for PASS_COUNT and PASS_RESOLVE
+    #[spirv(uniform, descriptor_set = 0, binding = 7)] n_threads: &u32,
+    // Bit `id.x` is set if the thread's range starts inside a quoted field. Must be zeroed by the
+    // caller.
+    #[spirv(storage_buffer, descriptor_set = 0, binding = 8)] in_quotes: &mut [u32],
+    #[spirv(workgroup)] shared: &mut scan::Shared,
+) {
+    let index = id.x as usize;
+
+    if *pass == PASS_COUNT {
+        let start: usize = index * (*chunk_size as usize);
+        let mut acc = 0;
+        for i in start..(start + *chunk_size as usize) {
+            if i < *data_len as usize && bytes::get(input, i) == *quote {
+                acc += 1;
+            }
+        }
+        // Every thread has its own entry, so no synchronization is needed
+        sums[index] = acc % 2;
+    } else if *pass == PASS_RESOLVE {
+        let mut state = scan::prefix(sums, *n_threads, id.x) & 1;
+        if *initial_state != 0 {
+            state ^= 1;
+        }
+        if state == 1 {
+            atomic::or(&mut in_quotes[index / 32], 1 << (index % 32));
+        }
+    } else {
+        scan::scan_block(sums, shared, *n_threads, *pass - PASS_SCAN, id.x, lid.x);
+    }
+}
@@ -1,14 +1,50 @@
+use std::path::{Path, PathBuf};
+
 use spirv_builder::{Capability, SpirvBuilder};
 
+/// Translates a kernel from SPIR-V to WGSL, for devices that can't take SPIR-V as it is. naga
+/// validates the kernel on the way.
+fn spirv_to_wgsl(spirv: &[u8]) -> Result<String, Box<dyn std::error::Error>> {
+    let module = naga::front::spv::parse_u8_slice(spirv, &naga::front::spv::Options::default())?;
+    let info = naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::all(),
+    )
+    .validate(&module)?;
+    Ok(naga::back::wgsl::write_string(
+        &module,
+        &info,
+        naga::back::wgsl::WriterFlags::empty(),
+    )?)
+}
+
+/// Writes the WGSL translation of the kernel at `spv_path` next to it, and points the
+/// `<kernel>.wgsl` environment variable at it like spirv-builder does for `<kernel>.spv`. A kernel
+/// that can't be translated gets a WGSL file without an entry point, so creating its pipeline
+/// fails on devices that need the WGSL.
+fn write_wgsl(spv_path: &Path) {
+    let name = spv_path.file_stem().unwrap().to_str().unwrap();
+    let spirv = std::fs::read(spv_path).expect("Error reading compiled kernel");
+    let wgsl = spirv_to_wgsl(&spirv).unwrap_or_else(|e| {
+        println!("cargo:warning={} can't be translated to WGSL: {}", name, e);
+        format!("// {} can't be translated to WGSL: {}\n", name, e)
+    });
+    let wgsl_path = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join(format!("{}.wgsl", name));
+    std::fs::write(&wgsl_path, wgsl).expect("Error writing WGSL");
+    println!("cargo:rustc-env={}.wgsl={}", name, wgsl_path.display());
+}
+
 fn main() {
     for kernel in std::fs::read_dir("../kernels").expect("Error finding kernels folder") {
         let path = kernel.expect("Invalid path in kernels folder").path();
         let compile_res = SpirvBuilder::new(&path, "spirv-unknown-vulkan1.1")
-            .capability(Capability::Int8)
+            .capability(Capability::Int64)
+            .capability(Capability::Float64)
             .capability(Capability::VulkanMemoryModelDeviceScope)
             .build()
             .expect("Kernel failed to compile");
 
         eprintln!("COMPILE RESULT: {:?}", compile_res);
+        write_wgsl(compile_res.module.unwrap_single());
     }
 }
@@ -0,0 +1,70 @@
+/// A packed bitmap with one bit per row, least significant bit first.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u32>,
+    len: usize,
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        assert!(i < self.len);
+        (self.words[i / 32] >> (i % 32)) & 1 == 1
+    }
+
+    /// Number of set bits
+    pub fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// The packed bits. Bits past `len` in the last word are always unset.
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = bool> + '_ {
+        (0..self.len).map(|i| self.get(i))
+    }
+
+    pub fn push(&mut self, bit: bool) {
+        self.extend_from_words(&[bit as u32], 1);
+    }
+
+    pub fn set(&mut self, i: usize, bit: bool) {
+        assert!(i < self.len);
+        if bit {
+            self.words[i / 32] |= 1 << (i % 32);
+        } else {
+            self.words[i / 32] &= !(1 << (i % 32));
+        }
+    }
+
+    /// Appends the first `len` bits of `words`
+    pub fn extend_from_words(&mut self, words: &[u32], len: usize) {
+        let shift = self.len % 32;
+        for (i, word) in words.iter().take(len.div_ceil(32)).enumerate() {
+            let n = std::cmp::min(32, len - i * 32);
+            let word = if n < 32 { word & ((1 << n) - 1) } else { *word };
+            if shift == 0 {
+                self.words.push(word);
+            } else {
+                *self.words.last_mut().unwrap() |= word << shift;
+                if n > 32 - shift {
+                    self.words.push(word >> (32 - shift));
+                }
+            }
+            self.len += n;
+        }
+    }
+}
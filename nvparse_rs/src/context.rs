@@ -0,0 +1,386 @@
+//! Owns everything that's expensive to set up for parsing on a GPU, so that it's done once and
+//! shared by every input parsed instead of being repeated for each one.
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use kernelcodegen::{scan, ComputeKernel};
+use wgpu::util::DeviceExt;
+use wgpu::{Adapter, Buffer, Device, Queue};
+
+use crate::driver;
+use crate::error::NvParseError;
+
+/// Number of input buffers that the producer fills while the consumer parses the others
+const N_INPUT_BUFS: usize = 8;
+
+/// Identifies an adapter among those of the backends an `AdapterSelector` allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdapterChoice {
+    /// Its position in the list returned by `GpuContext::adapters`
+    Index(usize),
+    /// Part of its name, ignoring case. The first adapter whose name matches is picked.
+    Name(String),
+}
+
+/// Parses an index, or failing that takes the string as part of a name
+impl FromStr for AdapterChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Missing adapter index or name".to_string());
+        }
+        Ok(match s.parse() {
+            Ok(index) => AdapterChoice::Index(index),
+            Err(_) => AdapterChoice::Name(s.to_string()),
+        })
+    }
+}
+
+/// Picks the adapter that a `GpuContext` parses on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdapterSelector {
+    /// Backends whose adapters may be picked, e.g. `wgpu::Backends::VULKAN`
+    pub backends: wgpu::Backends,
+    /// The adapter to pick, or `None` for the one wgpu prefers
+    pub adapter: Option<AdapterChoice>,
+}
+
+/// wgpu's preferred adapter of any backend
+impl Default for AdapterSelector {
+    fn default() -> Self {
+        AdapterSelector {
+            backends: wgpu::Backends::all(),
+            adapter: None,
+        }
+    }
+}
+
+impl AdapterSelector {
+    pub fn backends(mut self, backends: wgpu::Backends) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    pub fn adapter(mut self, adapter: Option<AdapterChoice>) -> Self {
+        self.adapter = adapter;
+        self
+    }
+}
+
+/// The compiled kernels, one for each crate in `kernels/`
+pub(crate) struct Kernels {
+    pub countchar: ComputeKernel,
+    pub getcharpos: ComputeKernel,
+    pub parsecsv: ComputeKernel,
+    pub quoteparity: ComputeKernel,
+    pub gatherstrings: ComputeKernel,
+    pub dictencode: ComputeKernel,
+}
+
+/// Creates the kernel of the crate `$name` on `$device`, from the SPIR-V compiled by build.rs and
+/// its translation to WGSL
+macro_rules! kernel {
+    ($device:expr, $name:ident) => {
+        $name::codegen::new(
+            $device,
+            include_bytes!(env!(concat!(stringify!($name), ".spv"))),
+            include_str!(env!(concat!(stringify!($name), ".wgsl"))),
+        )
+    };
+}
+
+impl Kernels {
+    fn new(device: &Device) -> Self {
+        Kernels {
+            countchar: kernel!(device, countchar),
+            getcharpos: kernel!(device, getcharpos),
+            parsecsv: kernel!(device, parsecsv),
+            quoteparity: kernel!(device, quoteparity),
+            gatherstrings: kernel!(device, gatherstrings),
+            dictencode: kernel!(device, dictencode),
+        }
+    }
+}
+
+/// Buffers that the kernels only ever read, so any number of pipelines can bind them at once.
+pub(crate) struct ConstantBuffers {
+    /// parsecsv's table of powers of five for parsing floats
+    pub powers_of_five: Buffer,
+    /// The pass uniform for each dispatch of quoteparity, indexed by the pass
+    pub quoteparity_passes: Vec<Buffer>,
+    /// The pass uniform for each dispatch of dictencode, indexed by the pass
+    pub dictencode_passes: Vec<Buffer>,
+    /// The pass uniform for each dispatch of gatherstrings, indexed by the pass
+    pub gatherstrings_passes: Vec<Buffer>,
+    /// The pass uniform for each dispatch of countchar, indexed by the pass
+    pub countchar_passes: Vec<Buffer>,
+    /// Bound in place of buffers that a dispatch never touches, such as the quote state when
+    /// fields aren't quoted
+    pub placeholder: Buffer,
+}
+
+impl ConstantBuffers {
+    fn new(device: &Device) -> Self {
+        let powers_of_five: Vec<u8> = parsecsv::POWERS_OF_FIVE
+            .iter()
+            .flat_map(|p| p.to_ne_bytes())
+            .collect();
+        let pass_buf = |kernel: &str, pass: &u32| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} pass {}", kernel, pass)),
+                contents: &pass.to_ne_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            })
+        };
+        ConstantBuffers {
+            powers_of_five: device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Powers of five"),
+                contents: &powers_of_five,
+                usage: wgpu::BufferUsages::STORAGE,
+            }),
+            // The kernels are dispatched once for each of their passes, which need their own
+            // uniforms since they're all encoded before the queue is submitted. Scans have a pass
+            // for each level, see `kernelcodegen::scan`.
+            quoteparity_passes: (quoteparity::PASS_COUNT..=quoteparity::PASS_RESOLVE)
+                .map(|pass| pass_buf("quoteparity", &pass))
+                .collect(),
+            dictencode_passes: (dictencode::PASS_INSERT..=dictencode::PASS_RESOLVE)
+                .map(|pass| pass_buf("dictencode", &pass))
+                .collect(),
+            gatherstrings_passes: (gatherstrings::PASS_COUNT..=gatherstrings::PASS_GATHER)
+                .map(|pass| pass_buf("gatherstrings", &pass))
+                .collect(),
+            countchar_passes: (countchar::PASS_COUNT..countchar::PASS_SCAN + scan::MAX_LEVELS)
+                .map(|pass| pass_buf("countchar", &pass))
+                .collect(),
+            placeholder: driver::create_output_buffer(device, "placeholder", 4),
+        }
+    }
+}
+
+/// Buffers that a pipeline writes to while it runs, so every running pipeline needs its own.
+pub(crate) struct ScratchBuffers {
+    /// The ring of buffers that the input is read into, chunk by chunk
+    pub inputs: Vec<Buffer>,
+    /// If the device can't map `inputs`, a buffer for each of them that the input is read into
+    /// instead, and copied from at the start of every chunk
+    pub staging: Option<Vec<Buffer>>,
+    /// Size of each buffer in `inputs`
+    pub input_size: u32,
+    pub chunk_size: Buffer,
+    pub initial_state: Buffer,
+    pub data_len: Buffer,
+    pub residual_len: Buffer,
+    /// Number of threads that the kernels are dispatched with for a pass over the whole chunk
+    pub n_threads: Buffer,
+    /// The number of lines in a chunk, copied out of countchar's scan to be read back
+    pub nlines: Buffer,
+}
+
+impl ScratchBuffers {
+    fn new(device: &Device) -> Self {
+        // Using a smaller size here seems to have better performance. Maybe because it provides
+        // more opportunities for compute to overlap with IO, hiding the latency? Mapped ranges
+        // need to be aligned to 4 bytes, so the buffer size is as well.
+        let input_size = (device.limits().max_storage_buffer_binding_size / 8) & !3;
+        log::debug!("max_buffer_size {}", input_size);
+        let input_bufs = |label: &str, usage: wgpu::BufferUsages| -> Vec<Buffer> {
+            (0..N_INPUT_BUFS)
+                .map(|i| {
+                    device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some(&format!("{} {}", label, i)),
+                        size: input_size as wgpu::BufferAddress,
+                        usage,
+                        mapped_at_creation: false,
+                    })
+                })
+                .collect()
+        };
+        let usage = driver::input_usages(device);
+        let inputs = input_bufs("File Input", usage);
+        let staging = (!usage.contains(wgpu::BufferUsages::MAP_WRITE)).then(|| {
+            input_bufs(
+                "Input staging",
+                wgpu::BufferUsages::MAP_WRITE | wgpu::BufferUsages::COPY_SRC,
+            )
+        });
+        let uniform = |label: &str| {
+            device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(label),
+                size: 4,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            })
+        };
+        ScratchBuffers {
+            inputs,
+            staging,
+            input_size,
+            chunk_size: uniform("Chunk size"),
+            initial_state: uniform("initial quote state"),
+            data_len: uniform("data_length"),
+            residual_len: uniform("residual_length"),
+            n_threads: uniform("thread count"),
+            nlines: device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("nlines"),
+                size: 4,
+                usage: driver::output_usages(device),
+                mapped_at_creation: false,
+            }),
+        }
+    }
+}
+
+/// A GPU set up for parsing: its adapter and device, the compiled kernels, and a pool of the
+/// buffers that don't depend on the schema. Sessions created with `Session::with_context` share
+/// all of these, so a process that parses many small inputs only pays for setting them up once.
+pub struct GpuContext {
+    adapter: Adapter,
+    device: Device,
+    queue: Queue,
+    pub(crate) kernels: Kernels,
+    pub(crate) constants: ConstantBuffers,
+    /// Scratch buffers that aren't in use by a running pipeline
+    scratch: Mutex<Vec<ScratchBuffers>>,
+    /// Held while an `ErrorScope` is open. wgpu keeps one stack of error scopes for the whole
+    /// device, so scopes opened by pipelines running at once would catch each other's errors.
+    scope: Mutex<()>,
+    /// The first error that wgpu reported outside of an `ErrorScope` since it was last checked,
+    /// see `check_errors`
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl GpuContext {
+    /// Sets up the default adapter for parsing
+    pub async fn new() -> Result<Self, NvParseError> {
+        GpuContext::with_selector(&AdapterSelector::default()).await
+    }
+
+    /// Every adapter of `backends`, in the order that `AdapterChoice::Index` counts them
+    pub fn adapters(backends: wgpu::Backends) -> Vec<Adapter> {
+        driver::enumerate_adapters(backends)
+    }
+
+    /// The features that the kernels need but `adapter` doesn't support. The adapter can only be
+    /// parsed on if there are none. Features that are only used if they're supported, such as
+    /// mapping storage buffers, aren't included.
+    pub fn missing_features(adapter: &Adapter) -> wgpu::Features {
+        driver::missing_features(adapter.features())
+    }
+
+    /// Sets up the adapter picked by `selector` for parsing
+    pub async fn with_selector(selector: &AdapterSelector) -> Result<Self, NvParseError> {
+        let adapter = driver::init_adapter(selector)
+            .await
+            .ok_or(NvParseError::NoAdapter)?;
+        let missing = GpuContext::missing_features(&adapter);
+        if !missing.is_empty() {
+            return Err(NvParseError::MissingFeatures(missing));
+        }
+        let (device, queue) = driver::init_device(&adapter).await?;
+        // wgpu panics on errors outside of an error scope by default. They're kept instead, to be
+        // returned by whichever pipeline checks for them next.
+        let error = Arc::new(Mutex::new(None));
+        device.on_uncaptured_error({
+            let error = error.clone();
+            Box::new(move |e| {
+                error.lock().unwrap().get_or_insert(e.to_string());
+            })
+        });
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let kernels = Kernels::new(&device);
+        let constants = ConstantBuffers::new(&device);
+        if let Some(e) = device.pop_error_scope().await {
+            return Err(e.into());
+        }
+        Ok(GpuContext {
+            adapter,
+            device,
+            queue,
+            kernels,
+            constants,
+            scratch: Mutex::new(Vec::new()),
+            scope: Mutex::new(()),
+            error,
+        })
+    }
+
+    pub fn adapter(&self) -> &Adapter {
+        &self.adapter
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Queue {
+        &self.queue
+    }
+
+    /// Opens an error scope, which catches the validation errors of whatever is encoded and
+    /// submitted until `ErrorScope::finish`. Other pipelines wait to open theirs until it's
+    /// finished, so it only catches the errors of the pipeline that opened it.
+    pub(crate) fn error_scope(&self) -> ErrorScope<'_> {
+        // A pipeline that panicked with a scope open has already popped it, see `ErrorScope::drop`
+        let guard = self.scope.lock().unwrap_or_else(|e| e.into_inner());
+        self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+        ErrorScope {
+            device: &self.device,
+            _guard: guard,
+            open: true,
+        }
+    }
+
+    /// Returns the error that wgpu reported outside of an error scope since the last check, if it
+    /// reported one. These are a fallback for work that isn't done in an `ErrorScope`, e.g. the
+    /// copies made to read buffers back. The device is shared, so with several pipelines running
+    /// at once the error may have come from another one.
+    pub(crate) fn check_errors(&self) -> Result<(), NvParseError> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(NvParseError::Validation(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Takes a set of scratch buffers out of the pool, or creates one if every set is in use
+    pub(crate) fn take_scratch(&self) -> ScratchBuffers {
+        let pooled = self.scratch.lock().unwrap().pop();
+        pooled.unwrap_or_else(|| ScratchBuffers::new(&self.device))
+    }
+
+    /// Returns scratch buffers taken with `take_scratch` to the pool
+    pub(crate) fn return_scratch(&self, scratch: ScratchBuffers) {
+        self.scratch.lock().unwrap().push(scratch);
+    }
+}
+
+/// The validation errors of one pipeline's work on a shared device, see `GpuContext::error_scope`
+pub(crate) struct ErrorScope<'a> {
+    device: &'a Device,
+    _guard: MutexGuard<'a, ()>,
+    /// Whether the scope is still on the device's stack
+    open: bool,
+}
+
+impl ErrorScope<'_> {
+    /// Closes the scope, returning the first validation error since it was opened
+    pub(crate) fn finish(mut self) -> Result<(), NvParseError> {
+        self.open = false;
+        // wgpu reports errors as they happen, so the future is ready straight away
+        match futures::executor::block_on(self.device.pop_error_scope()) {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A scope that isn't finished, because its pipeline returned early or panicked, is popped so
+/// that it doesn't catch the errors of the next one
+impl Drop for ErrorScope<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            drop(self.device.pop_error_scope());
+        }
+    }
+}
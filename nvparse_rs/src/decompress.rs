@@ -0,0 +1,307 @@
+//! Decompresses gzip, zstd and lz4 inputs on their own thread, so that decompressing the next
+//! block overlaps with copying the previous one into the GPU's input buffers.
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+
+use crate::driver::read_chunk;
+
+/// Bytes of decompressed input handed over at a time
+const BLOCK_SIZE: usize = 1 << 22;
+/// Blocks that can be decompressed ahead of the reader
+const N_BLOCKS: usize = 4;
+/// Length of the longest magic number that formats are detected from
+const MAGIC_LEN: usize = 4;
+
+/// A compressed format that inputs can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    /// The lz4 frame format, as written by the `lz4` command line tool
+    Lz4,
+}
+
+impl Compression {
+    /// Identifies the format of an input from the magic bytes at its start
+    pub fn detect(input: &[u8]) -> Option<Self> {
+        if input.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if input.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if input.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// Identifies the format of a file from its extension, e.g. `dump.csv.gz`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gz" | "gzip" => Ok(Compression::Gzip),
+            "zst" | "zstd" => Ok(Compression::Zstd),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!(
+                "Unknown compression {}, expected gzip, zstd or lz4",
+                s
+            )),
+        }
+    }
+}
+
+/// Reads `input`, decompressing it on another thread if it's compressed. The format is detected
+/// from the magic bytes at the start of `input`, or failing that from the extension of `path`.
+pub fn decompressed<R: BufRead + Send + 'static>(
+    mut input: R,
+    path: Option<&Path>,
+) -> io::Result<Box<dyn BufRead + Send>> {
+    // A single `fill_buf` can come back with fewer bytes than the magic number, e.g. from a pipe,
+    // so read the magic number out and put it back in front of the rest of the input
+    let mut magic = [0; MAGIC_LEN];
+    let len = read_chunk(&mut input, &mut magic)?;
+    let compression =
+        Compression::detect(&magic[..len]).or_else(|| path.and_then(Compression::from_path));
+    let input = io::Cursor::new(magic[..len].to_vec()).chain(input);
+    Ok(match compression {
+        Some(compression) => Box::new(Decompressor::spawn(input, compression)),
+        None => Box::new(input),
+    })
+}
+
+/// Opens the file at `path` for reading, decompressing it if it's compressed, see `decompressed`
+pub fn open(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    decompressed(BufReader::new(File::open(path)?), Some(path))
+}
+
+/// Reads the decompressed contents of an input, which is decompressed on another thread.
+pub struct Decompressor {
+    receiver: mpsc::Receiver<io::Result<Vec<u8>>>,
+    block: Vec<u8>,
+    offset: usize,
+    /// The decompressing thread, joined once it hangs up
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Decompressor {
+    /// Starts decompressing `input` as `compression` on a new thread. Decompression stays up to a
+    /// few blocks ahead of what has been read, and stops early if the `Decompressor` is dropped.
+    pub fn spawn<R: Read + Send + 'static>(input: R, compression: Compression) -> Self {
+        // Bounding the channel keeps the decompressed input from piling up in memory when parsing
+        // is the bottleneck
+        let (sender, receiver) = mpsc::sync_channel(N_BLOCKS);
+        let thread = thread::spawn(move || {
+            let mut decoder: Box<dyn Read> = match compression {
+                // Files written by concatenating gzip files, as `pigz` does, have several members
+                Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(input)),
+                Compression::Zstd => match zstd::Decoder::new(input) {
+                    Ok(decoder) => Box::new(decoder),
+                    Err(e) => {
+                        let _ = sender.send(Err(e));
+                        return;
+                    }
+                },
+                Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(input)),
+            };
+            loop {
+                let mut block = vec![0; BLOCK_SIZE];
+                let block = match read_chunk(&mut decoder, &mut block) {
+                    Ok(0) => return,
+                    Ok(len) => {
+                        block.truncate(len);
+                        Ok(block)
+                    }
+                    Err(e) => Err(e),
+                };
+                let failed = block.is_err();
+                // The reader hung up, so nothing is waiting on the rest of the input
+                if sender.send(block).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Decompressor {
+            receiver,
+            block: Vec::new(),
+            offset: 0,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Read for Decompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let len = std::cmp::min(available.len(), buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.consume(len);
+        Ok(len)
+    }
+}
+
+impl BufRead for Decompressor {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.offset == self.block.len() {
+            match self.receiver.recv() {
+                Ok(block) => {
+                    self.block = block?;
+                    self.offset = 0;
+                }
+                // The decompressing thread hangs up at the end of the input, or if it panicked
+                Err(_) => {
+                    if let Some(thread) = self.thread.take() {
+                        if thread.join().is_err() {
+                            return Err(io::Error::other("Decompressing thread panicked"));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(&self.block[self.offset..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.offset = std::cmp::min(self.offset + amt, self.block.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn sample() -> Vec<u8> {
+        (0..20000)
+            .flat_map(|i| format!("{},row {}\n", i, i).into_bytes())
+            .collect()
+    }
+
+    fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 0).unwrap(),
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(Vec::new());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn read_all(mut input: Box<dyn BufRead + Send>) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    /// Reads at most one byte at a time, like a slow pipe
+    struct Trickle(io::Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = std::cmp::min(buf.len(), 1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    struct Panics;
+
+    impl Read for Panics {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            panic!("read failed");
+        }
+    }
+
+    #[test]
+    fn detects_magic_bytes() {
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let compressed = compress(b"a,b\n", compression);
+            assert_eq!(Compression::detect(&compressed), Some(compression));
+        }
+        assert_eq!(Compression::detect(b"a,b\n"), None);
+        assert_eq!(Compression::detect(&[0x28, 0xb5]), None);
+        assert_eq!(Compression::detect(b""), None);
+    }
+
+    #[test]
+    fn detects_extensions() {
+        assert_eq!(
+            Compression::from_path(Path::new("a.csv.gz")),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("a.csv.zst")),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("a.csv.lz4")),
+            Some(Compression::Lz4)
+        );
+        assert_eq!(Compression::from_path(Path::new("a.csv")), None);
+        assert_eq!(Compression::from_path(Path::new("gz")), None);
+    }
+
+    #[test]
+    fn decompresses_short_reads() {
+        let data = sample();
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            // Each `fill_buf` only returns a single byte, so detection has to read several
+            let input = Trickle(io::Cursor::new(compress(&data, compression)));
+            let input = decompressed(BufReader::with_capacity(1, input), None).unwrap();
+            assert_eq!(read_all(input).unwrap(), data, "{:?}", compression);
+        }
+    }
+
+    #[test]
+    fn passes_through_uncompressed() {
+        let data = sample();
+        let input = BufReader::with_capacity(1, Trickle(io::Cursor::new(data.clone())));
+        assert_eq!(read_all(decompressed(input, None).unwrap()).unwrap(), data);
+        // Inputs shorter than the longest magic number
+        for data in [&b""[..], b"a", b"\x1f"] {
+            let input = decompressed(io::Cursor::new(data.to_vec()), None).unwrap();
+            assert_eq!(read_all(input).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_extension() {
+        // Magic bytes take precedence over the extension
+        let data = b"a,b\n".to_vec();
+        let path = Path::new("a.csv.gz");
+        let input = decompressed(io::Cursor::new(data.clone()), None).unwrap();
+        assert_eq!(read_all(input).unwrap(), data);
+        let input = decompressed(io::Cursor::new(data), Some(path)).unwrap();
+        assert!(read_all(input).is_err());
+    }
+
+    #[test]
+    fn reports_truncated_input() {
+        let mut compressed = compress(&sample(), Compression::Gzip);
+        compressed.truncate(compressed.len() / 2);
+        let input = decompressed(io::Cursor::new(compressed), None).unwrap();
+        assert!(read_all(input).is_err());
+    }
+
+    #[test]
+    fn reports_panics() {
+        let input = Decompressor::spawn(Panics, Compression::Lz4);
+        assert!(read_all(Box::new(input)).is_err());
+    }
+}
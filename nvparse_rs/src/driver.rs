@@ -1,36 +1,105 @@
 use wgpu::util::DeviceExt;
 
 use futures::channel::oneshot;
+use kernelcodegen::{bytes, scan};
 use std::convert::TryInto;
-use std::ops::RangeBounds;
-use std::sync::mpsc;
+use std::io::{self, BufRead, Read};
+use std::ops::{Bound, RangeBounds};
+use std::sync::{mpsc, Arc};
 use std::thread;
-use tqdm::pbar;
 use wgpu::{Adapter, BufferAsyncError, Device, Queue, RequestDeviceError};
 
-async fn init_adapter() -> Option<Adapter> {
-    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
-    instance
-        .request_adapter(&wgpu::RequestAdapterOptions {
-            power_preference: wgpu::PowerPreference::default(),
-            force_fallback_adapter: false,
-            compatible_surface: None,
-        })
-        .await
+use crate::bitmap::Bitmap;
+use crate::context::ScratchBuffers;
+use crate::context::{AdapterChoice, AdapterSelector, ConstantBuffers, GpuContext, Kernels};
+use crate::error::NvParseError;
+use crate::schema::{Column, ColumnType, Schema};
+use crate::strings::Strings;
+
+fn instance(backends: wgpu::Backends) -> wgpu::Instance {
+    wgpu::Instance::new(wgpu::InstanceDescriptor {
+        backends,
+        ..Default::default()
+    })
+}
+
+/// Every adapter of `backends`, in the order `AdapterChoice::Index` counts them
+pub(crate) fn enumerate_adapters(backends: wgpu::Backends) -> Vec<Adapter> {
+    instance(backends).enumerate_adapters(backends)
+}
+
+pub(crate) async fn init_adapter(selector: &AdapterSelector) -> Option<Adapter> {
+    let adapters = || enumerate_adapters(selector.backends).into_iter();
+    match &selector.adapter {
+        None => {
+            instance(selector.backends)
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::default(),
+                    force_fallback_adapter: false,
+                    compatible_surface: None,
+                })
+                .await
+        }
+        Some(AdapterChoice::Index(index)) => adapters().nth(*index),
+        Some(AdapterChoice::Name(name)) => {
+            let name = name.to_lowercase();
+            adapters().find(|adapter| adapter.get_info().name.to_lowercase().contains(&name))
+        }
+    }
+}
+
+/// Features the device needs to run the kernels' translation to WGSL, which is used if it can't
+/// take their SPIR-V as it is
+pub(crate) const WGSL_FEATURES: wgpu::Features =
+    wgpu::Features::SHADER_INT64.union(wgpu::Features::SHADER_F64);
+
+/// Features that are used if the adapter has them. Without `MAPPABLE_PRIMARY_BUFFERS`, the input
+/// and output go through staging buffers, see `input_usages` and `read_buffer`. Without
+/// `SPIRV_SHADER_PASSTHROUGH`, the kernels are created from WGSL.
+pub(crate) const OPTIONAL_FEATURES: wgpu::Features = wgpu::Features::TIMESTAMP_QUERY
+    .union(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS)
+    .union(wgpu::Features::SPIRV_SHADER_PASSTHROUGH);
+
+/// The features that an adapter with `features` is missing to run the kernels
+pub(crate) fn missing_features(features: wgpu::Features) -> wgpu::Features {
+    if features.contains(wgpu::Features::SPIRV_SHADER_PASSTHROUGH) {
+        wgpu::Features::empty()
+    } else {
+        WGSL_FEATURES.difference(features)
+    }
 }
 
-async fn init_device(adapter: &Adapter) -> Result<(Device, Queue), RequestDeviceError> {
-    // let mut required_limits = adapter.limits();
-    // required_limits.max_storage_buffer_binding_size = 2<<30 - 1;
-    // required_limits.max_buffer_size = 2<<30 - 1;
+/// Limits to request from `adapter`: the downlevel defaults, with enough storage buffers for every
+/// kernel, and buffers as large as the adapter allows since they bound the size of a chunk
+fn required_limits(adapter: &Adapter) -> wgpu::Limits {
+    let adapter_limits = adapter.limits();
+    let defaults = wgpu::Limits::downlevel_defaults().using_resolution(adapter_limits.clone());
+    let storage_buffers = [
+        countchar::codegen::STORAGE_BUFFERS,
+        getcharpos::codegen::STORAGE_BUFFERS,
+        parsecsv::codegen::STORAGE_BUFFERS,
+        quoteparity::codegen::STORAGE_BUFFERS,
+        gatherstrings::codegen::STORAGE_BUFFERS,
+        dictencode::codegen::STORAGE_BUFFERS,
+    ];
+    let storage_buffers = storage_buffers.into_iter().max().unwrap();
+    wgpu::Limits {
+        max_storage_buffers_per_shader_stage: defaults
+            .max_storage_buffers_per_shader_stage
+            .max(storage_buffers),
+        max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+        max_buffer_size: adapter_limits.max_buffer_size,
+        ..defaults
+    }
+}
+
+pub(crate) async fn init_device(adapter: &Adapter) -> Result<(Device, Queue), RequestDeviceError> {
     adapter
         .request_device(
             &wgpu::DeviceDescriptor {
                 label: Some("mydevice"),
-                required_features: wgpu::Features::TIMESTAMP_QUERY
-                    | wgpu::Features::MAPPABLE_PRIMARY_BUFFERS
-                    | wgpu::Features::SPIRV_SHADER_PASSTHROUGH,
-                required_limits: adapter.limits(),
+                required_features: (WGSL_FEATURES | OPTIONAL_FEATURES) & adapter.features(),
+                required_limits: required_limits(adapter),
                 memory_hints: Default::default(),
             },
             None,
@@ -40,7 +109,7 @@ async fn init_device(adapter: &Adapter) -> Result<(Device, Queue), RequestDevice
 
 fn store_u32(queue: &Queue, buffer: &wgpu::Buffer, value: u32) {
     let bytes_per_u32 = std::num::NonZero::<u64>::new(4).unwrap();
-    let mut write_view = queue.write_buffer_with(&buffer, 0, bytes_per_u32).unwrap();
+    let mut write_view = queue.write_buffer_with(buffer, 0, bytes_per_u32).unwrap();
     write_view.as_mut().clone_from_slice(&value.to_ne_bytes());
 }
 
@@ -71,26 +140,81 @@ fn bind_buffers_and_run(
         entries,
     });
     cpass.set_bind_group(0, &bind_group, &[]);
-    cpass.set_pipeline(&compute_pipeline);
+    cpass.set_pipeline(compute_pipeline);
     cpass.dispatch_workgroups(workgroups.0, workgroups.1, workgroups.2);
 }
 
+/// Whether the device can map storage buffers, instead of copying through a staging buffer
+fn maps_storage(device: &Device) -> bool {
+    device
+        .features()
+        .contains(wgpu::Features::MAPPABLE_PRIMARY_BUFFERS)
+}
+
+/// Usages of the buffers the input is read into. They're written to by mapping them if the device
+/// allows it, or by copying from a staging buffer otherwise.
+pub(crate) fn input_usages(device: &Device) -> wgpu::BufferUsages {
+    let usages =
+        wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+    if maps_storage(device) {
+        usages | wgpu::BufferUsages::MAP_WRITE
+    } else {
+        usages
+    }
+}
+
+/// Usages of the buffers the kernels write to. They can be read back to the CPU by mapping them if
+/// the device allows it, or by copying to a staging buffer otherwise, see `read_buffer`.
+pub(crate) fn output_usages(device: &Device) -> wgpu::BufferUsages {
+    let usages =
+        wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST;
+    if maps_storage(device) {
+        usages | wgpu::BufferUsages::MAP_READ
+    } else {
+        usages
+    }
+}
+
 fn read_buffer<S: RangeBounds<wgpu::BufferAddress>>(
     device: &Device,
+    queue: &Queue,
     buffer: &wgpu::Buffer,
     range: S,
-) -> Vec<u32> {
+) -> Result<Vec<u32>, BufferAsyncError> {
+    if !buffer.usage().contains(wgpu::BufferUsages::MAP_READ) {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => buffer.size(),
+        };
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback staging"),
+            size: end - start,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("copy to staging"),
+        });
+        encoder.copy_buffer_to_buffer(buffer, start, &staging, 0, end - start);
+        queue.submit(Some(encoder.finish()));
+        return read_buffer(device, queue, &staging, ..);
+    }
     // Map the readback_buffer to the CPU
     let buffer_slice = buffer.slice(range);
     let (resolver, waiter) = oneshot::channel();
     buffer_slice.map_async(wgpu::MapMode::Read, move |res| {
-        resolver.send(res).unwrap();
+        let _ = resolver.send(res);
     });
-    // Wait for the buffer to be mapped and ready for reading
+    // Wait for the buffer to be mapped and ready for reading. The callback is dropped without
+    // being called if the device is lost.
     device.poll(wgpu::Maintain::Wait);
-    futures::executor::block_on(waiter)
-        .unwrap()
-        .expect("Mapping failed");
+    futures::executor::block_on(waiter).unwrap_or(Err(BufferAsyncError))?;
 
     // Copy from GPU to CPU
     let x = buffer_slice
@@ -100,77 +224,491 @@ fn read_buffer<S: RangeBounds<wgpu::BufferAddress>>(
         .collect::<Vec<_>>();
     // Unmap the GPU buffer so that it can be re-used in the next iteration
     buffer.unmap();
-    x
+    Ok(x)
 }
 
-fn consume_buffer(
-    total_len: usize,
-    device: std::sync::Arc<Device>,
-    queue: &Queue,
-    input_bufs: &std::sync::Arc<Vec<wgpu::Buffer>>,
+/// Number of errors parsecsv can record per column for each chunk. Errors past this are counted,
+/// but not reported.
+pub const MAX_ERRORS_PER_CHUNK: usize = 1024;
+
+/// Number of workgroups to dispatch for a pass over `data_len` bytes, and how many bytes each
+/// invocation should process.
+pub(crate) fn dispatch_size(data_len: u32, workgroup_size: u32, max_workgroups: u32) -> (u32, u32) {
+    let n_dispatches = std::cmp::min(1 + data_len / workgroup_size, max_workgroups);
+    let chunk_size = data_len / (n_dispatches * workgroup_size) + 1;
+    (n_dispatches, chunk_size)
+}
+
+/// The passes of a kernel that scan the values of `n_threads` threads, numbered from the kernel's
+/// `first_pass`, and the number of workgroups to dispatch for each
+pub(crate) fn scan_passes(first_pass: u32, n_threads: u32) -> impl Iterator<Item = (u32, u32)> {
+    (0..scan::levels(n_threads))
+        .map(move |level| (first_pass + level, scan::level_blocks(n_threads, level)))
+}
+
+/// Reads from `input` until `buf` is full or the input ends, returning the number of bytes read
+pub(crate) fn read_chunk<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Tracks the partial line at the end of the chunks seen so far, so that it can be stitched onto
+/// the first line of the next chunk that contains a line terminator.
+pub(crate) struct LineStitcher {
     char: u8,
-    receiver: mpsc::Receiver<(usize, usize, usize)>,
+    quote: Option<u8>,
+    carry: Vec<u8>,
+    /// Whether the chunks seen so far end inside a quoted field
+    in_quotes: bool,
+}
+
+impl LineStitcher {
+    pub fn new(char: u8, quote: Option<u8>) -> Self {
+        LineStitcher {
+            char,
+            quote,
+            carry: Vec::new(),
+            in_quotes: false,
+        }
+    }
+
+    /// Whether the next chunk starts inside a quoted field
+    pub fn in_quotes(&self) -> bool {
+        self.in_quotes
+    }
+
+    /// Offsets of the first and last line terminators in `slice` that aren't inside quotes, and
+    /// whether the slice ends inside quotes.
+    fn find_terminators(&self, slice: &[u8]) -> (Option<(usize, usize)>, bool) {
+        let Some(quote) = self.quote else {
+            let first = slice.iter().position(|c| *c == self.char);
+            let last = slice.iter().rposition(|c| *c == self.char);
+            return (first.zip(last), false);
+        };
+
+        let n_quotes = slice.iter().filter(|c| **c == quote).count();
+        let end_in_quotes = self.in_quotes ^ (n_quotes % 2 == 1);
+
+        let mut in_quotes = self.in_quotes;
+        let mut first = None;
+        for (i, c) in slice.iter().enumerate() {
+            if *c == quote {
+                in_quotes = !in_quotes;
+            } else if *c == self.char && !in_quotes {
+                first = Some(i);
+                break;
+            }
+        }
+        // Walk back from the end, where the state is known, to find the last terminator
+        let mut in_quotes = end_in_quotes;
+        let mut last = None;
+        for (i, c) in slice.iter().enumerate().rev() {
+            if *c == quote {
+                in_quotes = !in_quotes;
+            } else if *c == self.char && !in_quotes {
+                last = Some(i);
+                break;
+            }
+        }
+        (first.zip(last), end_in_quotes)
+    }
+
+    /// Whether `slice` ends the input without ending its last line, in which case a line
+    /// terminator is appended to it before it's parsed
+    pub fn needs_terminator(&self, slice: &[u8], last: bool) -> bool {
+        last && slice.last() != Some(&self.char)
+    }
+
+    /// Returns the residual for the next chunk `slice` (see `Chunk::residual`). If `last` is set,
+    /// the chunk ends the input and is treated as if it ended with a line terminator. An input
+    /// that ends inside a quoted field is malformed, since its last line can't be split up.
+    pub fn next_residual(&mut self, slice: &[u8], last: bool) -> Result<Vec<u8>, NvParseError> {
+        let mut residual = Vec::new();
+        let (terminators, end_in_quotes) = self.find_terminators(slice);
+        if last && end_in_quotes {
+            return Err(NvParseError::MalformedInput(
+                "the input ends inside a quoted field".to_string(),
+            ));
+        }
+        let terminate = self.needs_terminator(slice, last);
+        self.in_quotes = end_in_quotes;
+        match terminators {
+            Some((first, last_terminator)) => {
+                residual.append(&mut self.carry);
+                residual.extend_from_slice(&slice[..=first]);
+                self.carry
+                    .extend_from_slice(&slice[(last_terminator + 1)..]);
+            }
+            None => {
+                self.carry.extend_from_slice(slice);
+                if terminate {
+                    residual.append(&mut self.carry);
+                    residual.push(self.char);
+                }
+            }
+        }
+        Ok(residual)
+    }
+}
+
+/// Describes how lines are split up and which fields get parsed.
+#[derive(Clone)]
+pub(crate) struct PipelineConfig {
+    /// Line terminator
+    pub char: u8,
+    /// Whether a `\r` before the line terminator is dropped from the line
+    pub strip_cr: bool,
+    /// Field delimiter
+    pub delimiter: u8,
+    /// Character that fields containing delimiters or line terminators are enclosed in, with
+    /// quotes inside the field doubled as in RFC 4180. `None` if fields are never quoted.
+    pub quote: Option<u8>,
+    /// Decimal separator of floats
+    pub decimal: u8,
+    /// Fields to parse out of every line
+    pub columns: Vec<Column>,
+    /// parsecsv's FORMAT_* items laying out the dates or timestamps of each column
+    pub formats: Vec<Vec<u32>>,
+}
+
+impl PipelineConfig {
+    pub fn new(schema: &Schema) -> Self {
+        PipelineConfig {
+            char: schema.dialect.terminator.byte(),
+            strip_cr: schema.dialect.terminator.strips_cr(),
+            delimiter: schema.dialect.delimiter,
+            quote: schema.dialect.quote,
+            decimal: schema.dialect.decimal,
+            columns: schema.columns.clone(),
+            formats: schema
+                .columns
+                .iter()
+                .map(|column| schema.dialect.datetime_format(column.ty).items().to_vec())
+                .collect(),
+        }
+    }
+}
+
+/// A chunk of the input that has been copied into one of the input buffers
+struct Chunk {
+    input_buf_id: usize,
+    /// Number of bytes written to the input buffer
+    len: usize,
+    /// The first line of the chunk including its terminator, prefixed by the partial line left
+    /// over at the end of the previous chunk. Empty if the chunk has no line terminators.
+    residual: Vec<u8>,
+    /// Whether the chunk starts inside a quoted field
+    in_quotes: bool,
+    /// Index of the input the chunk was read from
+    input: u32,
+}
+
+/// The values parsed out of a single column.
+#[derive(Clone)]
+pub(crate) struct ColumnOutput {
+    pub ty: ColumnType,
+    /// Raw u32 words written by parsecsv, see `parsecsv::type_width`. Empty for string columns,
+    /// and the code of every row for dictionary columns.
+    pub words: Vec<u32>,
+    /// The values of a string column, or the dictionary of a dictionary column
+    pub strings: Strings,
+    pub validity: Bitmap,
+    /// (line, parsecsv error kind) of every error parsecsv recorded
+    pub errors: Vec<(u64, u32)>,
+    /// Number of errors that occurred, including the ones that weren't recorded
+    pub n_errors: u64,
+}
+
+impl ColumnOutput {
+    pub fn new(ty: ColumnType) -> Self {
+        ColumnOutput {
+            ty,
+            words: Vec::new(),
+            strings: Strings::new(),
+            validity: Bitmap::new(),
+            errors: Vec::new(),
+            n_errors: 0,
+        }
+    }
+
+    /// Appends dictionary `codes` into `dictionary`, merging `dictionary` into `self.strings`
+    fn append_codes(&mut self, codes: &[u32], dictionary: Strings) {
+        let remap = self.strings.merge(dictionary);
+        // Null rows hold code 0, which may not be in the dictionary
+        self.words.extend(
+            codes
+                .iter()
+                .map(|&code| remap.get(code as usize).copied().unwrap_or(0)),
+        );
+    }
+
+    /// Appends the output buffers of parsecsv for a chunk of `nlines` lines, the first of which
+    /// is line `first_line` of the input. `strings` holds the offsets and values compacted by
+    /// gatherstrings: the strings of every row for a string column, or the dictionary that
+    /// `words` index into for a dictionary column.
+    pub fn append_chunk(
+        &mut self,
+        words: &[u32],
+        strings: Option<(&[u32], Vec<u8>)>,
+        validity: &[u32],
+        errors: &[u32],
+        nlines: u32,
+        first_line: u64,
+    ) {
+        let first_row = self.validity.len();
+        self.validity.extend_from_words(validity, nlines as usize);
+
+        let n_errors = errors[0] as usize;
+        self.n_errors += n_errors as u64;
+        let n_recorded = std::cmp::min(n_errors, MAX_ERRORS_PER_CHUNK);
+        for entry in errors[1..]
+            .chunks_exact(parsecsv::ERROR_ENTRY_WIDTH)
+            .take(n_recorded)
+        {
+            self.errors.push((first_line + entry[0] as u64, entry[1]));
+        }
+
+        let (strings, invalid) = match strings {
+            Some((offsets, values)) => Strings::from_gathered(offsets, values),
+            None => (Strings::new(), Vec::new()),
+        };
+        let invalid_rows = match self.ty {
+            ColumnType::Utf8 => {
+                self.strings.append(strings);
+                invalid
+            }
+            ColumnType::Dictionary => {
+                let invalid_rows = codes_of(words, validity, &invalid);
+                self.append_codes(words, strings);
+                invalid_rows
+            }
+            _ => {
+                self.words.extend_from_slice(words);
+                Vec::new()
+            }
+        };
+
+        // Strings that aren't UTF-8 can only be caught once they've been read back
+        self.n_errors += invalid_rows.len() as u64;
+        let n_recorded_strings = MAX_ERRORS_PER_CHUNK - n_recorded;
+        for (i, row) in invalid_rows.into_iter().enumerate() {
+            self.validity.set(first_row + row, false);
+            if i < n_recorded_strings {
+                self.errors
+                    .push((first_line + row as u64, parsecsv::ERROR_INVALID));
+            }
+        }
+    }
+}
+
+/// The valid rows whose code is one of `entries`
+fn codes_of(codes: &[u32], validity: &[u32], entries: &[usize]) -> Vec<usize> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+    codes
+        .iter()
+        .enumerate()
+        .filter(|(row, code)| {
+            (validity[row / 32] >> (row % 32)) & 1 == 1 && entries.contains(&(**code as usize))
+        })
+        .map(|(row, _)| row)
+        .collect()
+}
+
+impl ColumnOutput {
+    /// Appends the output of the chunk that follows the ones already in `self`
+    pub fn append(&mut self, other: ColumnOutput) {
+        if self.ty == ColumnType::Dictionary {
+            self.append_codes(&other.words, other.strings);
+        } else {
+            self.words.extend(other.words);
+            self.strings.append(other.strings);
+        }
+        self.validity
+            .extend_from_words(other.validity.words(), other.validity.len());
+        self.errors.extend(other.errors);
+        self.n_errors += other.n_errors;
+    }
+}
+
+/// Output of a pass over (part of) the input: the number of lines seen and, for each requested
+/// column, the values parsed out of that field of every line.
+pub(crate) struct PipelineOutput {
+    pub nlines: u64,
+    pub columns: Vec<ColumnOutput>,
+    /// Runs of consecutive lines that were read from the same input, as the index of the input
+    /// and the number of lines in the run
+    pub inputs: Vec<(u32, u64)>,
+}
+
+impl PipelineOutput {
+    pub fn new(columns: &[Column]) -> Self {
+        PipelineOutput {
+            nlines: 0,
+            columns: columns
+                .iter()
+                .map(|column| ColumnOutput::new(column.ty))
+                .collect(),
+            inputs: Vec::new(),
+        }
+    }
+}
+
+/// Receives the output of every chunk as soon as it has been read back, in input order. Chunks
+/// without a line terminator are skipped, since their lines are parsed with a later chunk.
+pub(crate) trait ChunkSink: Send + 'static {
+    /// Fails if the output of the chunk is corrupt, which stops the pipeline
+    fn push_chunk(&mut self, chunk: PipelineOutput) -> Result<(), NvParseError>;
+}
+
+/// Collects the output of the whole input
+impl ChunkSink for PipelineOutput {
+    fn push_chunk(&mut self, chunk: PipelineOutput) -> Result<(), NvParseError> {
+        self.nlines += chunk.nlines;
+        for (column, chunk_column) in self.columns.iter_mut().zip(chunk.columns) {
+            column.append(chunk_column);
+        }
+        for (input, nlines) in chunk.inputs {
+            match self.inputs.last_mut() {
+                Some((last, run)) if *last == input => *run += nlines,
+                _ => self.inputs.push((input, nlines)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Size in bytes of the errors buffer bound to parsecsv
+pub(crate) fn errors_buffer_size() -> usize {
+    (1 + MAX_ERRORS_PER_CHUNK * parsecsv::ERROR_ENTRY_WIDTH) * 4
+}
+
+/// Creates a storage buffer that the kernels can write to and that can be read back to the CPU
+pub(crate) fn create_output_buffer(device: &Device, label: &str, size: u32) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: size as wgpu::BufferAddress,
+        usage: output_usages(device),
+        mapped_at_creation: false,
+    })
+}
+
+fn consume_buffer<S: ChunkSink>(
+    context: &GpuContext,
+    scratch: &ScratchBuffers,
+    config: &PipelineConfig,
+    receiver: mpsc::Receiver<Chunk>,
     free_buffer: mpsc::Sender<usize>,
-) -> u32 {
+    mut sink: S,
+) -> Result<S, NvParseError> {
+    let device = context.device();
+    let queue = context.queue();
     let limits = device.limits();
 
-    let mut acc = 0;
-    let mut compute_pbar = pbar(Some(total_len));
+    // Lines seen so far, which can be more than fit in a u32 once several inputs are streamed
+    let mut acc: u64 = 0;
+    let columns = &config.columns;
 
     let timer = std::time::Instant::now();
 
-    let countchar_gen = countchar::codegen::new(&device, include_bytes!(env!("countchar.spv")));
-    let parsecsv_gen = parsecsv::codegen::new(&device, include_bytes!(env!("parsecsv.spv")));
-    let getcharpos_gen = getcharpos::codegen::new(&device, include_bytes!(env!("getcharpos.spv")));
-
-    let chunk_size_buf = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("Chunk size"),
-        size: 4,
-        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
+    let Kernels {
+        countchar: countchar_gen,
+        getcharpos: getcharpos_gen,
+        parsecsv: parsecsv_gen,
+        quoteparity: quoteparity_gen,
+        gatherstrings: gatherstrings_gen,
+        dictencode: dictencode_gen,
+    } = &context.kernels;
+    let ConstantBuffers {
+        powers_of_five: powers_of_five_buf,
+        quoteparity_passes: pass_bufs,
+        dictencode_passes: dictencode_pass_bufs,
+        gatherstrings_passes: gatherstrings_pass_bufs,
+        countchar_passes: countchar_pass_bufs,
+        placeholder: placeholder_buf,
+    } = &context.constants;
+    let ScratchBuffers {
+        inputs: input_bufs,
+        staging: staging_bufs,
+        chunk_size: chunk_size_buf,
+        initial_state: initial_state_buf,
+        data_len: data_len_buf,
+        residual_len: residual_len_buf,
+        n_threads: n_threads_buf,
+        nlines: nlines_buf,
+        ..
+    } = scratch;
 
     let char_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: Some("Character to match"),
-        contents: &[char],
+        contents: &u32::from(config.char).to_ne_bytes(),
         usage: wgpu::BufferUsages::UNIFORM,
     });
 
     let delimeter_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some("Character to match"),
-        contents: &[b'|'],
+        label: Some("Delimiter"),
+        contents: &u32::from(config.delimiter).to_ne_bytes(),
         usage: wgpu::BufferUsages::UNIFORM,
     });
 
-    let data_len_buf = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("data_length"),
-        size: 4,
-        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
+    let strip_cr_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Strip CR"),
+        contents: &(config.strip_cr as u32).to_ne_bytes(),
+        usage: wgpu::BufferUsages::UNIFORM,
     });
 
-    let output_buf = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("count (output)"),
-        size: (countchar_gen.workgroup_dim.0 * 4) as wgpu::BufferAddress,
-        // Can be read to the CPU, and can be copied from the shader's storage buffer
-        usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::MAP_READ
-            | wgpu::BufferUsages::COPY_SRC
-            | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
+    let quote_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Quote"),
+        contents: &u32::from(config.quote.unwrap_or(0)).to_ne_bytes(),
+        usage: wgpu::BufferUsages::UNIFORM,
     });
 
-    let nlines_buf = device.create_buffer(&wgpu::BufferDescriptor {
-        label: Some("nlines_per_thread"),
-        size: (countchar_gen.workgroup_dim.0 * 4) as wgpu::BufferAddress,
-        // Can be read to the CPU, and can be copied from the shader's storage buffer
-        usage: wgpu::BufferUsages::STORAGE
-            | wgpu::BufferUsages::MAP_READ
-            | wgpu::BufferUsages::COPY_SRC
-            | wgpu::BufferUsages::COPY_DST,
-        mapped_at_creation: false,
+    let decimal_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Decimal separator"),
+        contents: &u32::from(config.decimal).to_ne_bytes(),
+        usage: wgpu::BufferUsages::UNIFORM,
     });
 
+    // Each requested column is parsed by its own dispatch of parsecsv, so every column needs its
+    // own uniforms describing the field.
+    let column_bufs: Vec<[wgpu::Buffer; 4]> = columns
+        .iter()
+        .zip(&config.formats)
+        .map(|(column, format)| {
+            let index_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} index", column.name)),
+                contents: &column.field_index().to_ne_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let type_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} type", column.name)),
+                contents: &column.ty.kernel_type().to_ne_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let nullable_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} nullable", column.name)),
+                contents: &(column.nullable as u32).to_ne_bytes(),
+                usage: wgpu::BufferUsages::UNIFORM,
+            });
+            let format: Vec<u8> = format.iter().flat_map(|item| item.to_ne_bytes()).collect();
+            let format_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} format", column.name)),
+                contents: &format,
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+            [index_buf, type_buf, nullable_buf, format_buf]
+        })
+        .collect();
+
     let setup_dur = timer.elapsed();
     let mut encoder_dur = std::time::Duration::ZERO;
     let mut submit_dur = std::time::Duration::ZERO;
@@ -182,19 +720,24 @@ fn consume_buffer(
 
     loop {
         let timer = std::time::Instant::now();
-        let (offset, end, input_buf_id) = receiver.recv().unwrap();
+        // The producer hangs up once it has sent the last chunk
+        let Ok(chunk) = receiver.recv() else {
+            break;
+        };
+        let input_buf_id = chunk.input_buf_id;
         wait_dur += timer.elapsed();
         let timer = std::time::Instant::now();
-        let data_len = (end - offset) as u32;
+        let scope = context.error_scope();
+        let data_len = chunk.len as u32;
         // For storing a single u32 into a buffer, the intermediate copy isn't expensive
-        store_u32(&queue, &data_len_buf, data_len);
-        let n_dispatches = std::cmp::min(
-            1 + data_len / countchar_gen.workgroup_dim.0,
+        store_u32(queue, data_len_buf, data_len);
+        let (n_dispatches, chunk_size) = dispatch_size(
+            data_len,
+            countchar_gen.workgroup_dim.0,
             limits.max_compute_workgroups_per_dimension,
         );
-        let chunk_size: u32 = data_len / (n_dispatches * countchar_gen.workgroup_dim.0) + 1;
         max_chunk_size = std::cmp::max(chunk_size, max_chunk_size);
-        store_u32(&queue, &chunk_size_buf, chunk_size);
+        store_u32(queue, chunk_size_buf, chunk_size);
         write_uniform_dur += timer.elapsed();
 
         let timer = std::time::Instant::now();
@@ -203,177 +746,480 @@ fn consume_buffer(
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("do compute"),
         });
+        if let Some(staging_bufs) = staging_bufs {
+            // Copies are made in whole words, and the input buffers are sized to fit them
+            let copy_len = chunk.len.next_multiple_of(4) as wgpu::BufferAddress;
+            let (staging_buf, input_buf) = (&staging_bufs[input_buf_id], &input_bufs[input_buf_id]);
+            encoder.copy_buffer_to_buffer(staging_buf, 0, input_buf, 0, copy_len);
+        }
         let dispatch = (n_dispatches, 1, 1);
-        // eprintln!("dispatch={:?} chunk_size={:?} data_len={:?}", dispatch, chunk_size, data_len);
-        bind_buffers_and_run(
-            &mut encoder,
-            &device,
-            &countchar_gen.compute_pipeline,
-            &countchar_gen.bind_group_layout,
-            &[
-                &input_bufs[input_buf_id],
-                &chunk_size_buf,
-                &data_len_buf,
-                &char_buf,
-                &output_buf,
-            ],
-            dispatch,
-        );
 
-        encoder.copy_buffer_to_buffer(&output_buf, 0, &nlines_buf, 0, nlines_buf.size());
+        let n_threads = n_dispatches * countchar_gen.workgroup_dim.0;
+        store_u32(queue, n_threads_buf, n_threads);
+
+        // [quote parities and the levels of their scan, in quotes], see quoteparity
+        let quote_state_bufs = config.quote.map(|_| {
+            [
+                create_output_buffer(device, "quote parities", scan::sums_len(n_threads) * 4),
+                create_output_buffer(device, "in quotes", n_threads.div_ceil(32) * 4),
+            ]
+        });
+        if let Some([parity_sums_buf, in_quotes_buf]) = &quote_state_bufs {
+            store_u32(queue, initial_state_buf, chunk.in_quotes as u32);
+            let passes = std::iter::once((quoteparity::PASS_COUNT, n_dispatches))
+                .chain(scan_passes(quoteparity::PASS_SCAN, n_threads))
+                .chain(std::iter::once((quoteparity::PASS_RESOLVE, n_dispatches)));
+            for (pass, n_workgroups) in passes {
+                bind_buffers_and_run(
+                    &mut encoder,
+                    device,
+                    &quoteparity_gen.compute_pipeline,
+                    &quoteparity_gen.bind_group_layout,
+                    &[
+                        &input_bufs[input_buf_id],
+                        chunk_size_buf,
+                        data_len_buf,
+                        &quote_buf,
+                        &pass_bufs[pass as usize],
+                        initial_state_buf,
+                        parity_sums_buf,
+                        n_threads_buf,
+                        in_quotes_buf,
+                    ],
+                    (n_workgroups, 1, 1),
+                );
+            }
+        }
+        let in_quotes_buf = quote_state_bufs
+            .as_ref()
+            .map_or(placeholder_buf, |bufs| &bufs[1]);
+
+        // The count of lines of every thread, then the levels of their scan, see countchar
+        let sums_buf = create_output_buffer(device, "line counts", scan::sums_len(n_threads) * 4);
+        let passes = std::iter::once((countchar::PASS_COUNT, n_dispatches))
+            .chain(scan_passes(countchar::PASS_SCAN, n_threads));
+        for (pass, n_workgroups) in passes {
+            bind_buffers_and_run(
+                &mut encoder,
+                device,
+                &countchar_gen.compute_pipeline,
+                &countchar_gen.bind_group_layout,
+                &[
+                    &input_bufs[input_buf_id],
+                    chunk_size_buf,
+                    data_len_buf,
+                    &char_buf,
+                    &sums_buf,
+                    &quote_buf,
+                    in_quotes_buf,
+                    &countchar_pass_bufs[pass as usize],
+                    n_threads_buf,
+                ],
+                (n_workgroups, 1, 1),
+            );
+        }
+
+        let total_offset = scan::total_offset(n_threads) as wgpu::BufferAddress * 4;
+        encoder.copy_buffer_to_buffer(&sums_buf, total_offset, nlines_buf, 0, nlines_buf.size());
 
         encoder_dur += timer.elapsed();
         let timer = std::time::Instant::now();
 
         // Run the queued computation
         queue.submit(Some(encoder.finish()));
+        // The counts are garbage if the kernels couldn't run
+        scope.finish()?;
 
         submit_dur += timer.elapsed();
 
         let output_timer = std::time::Instant::now();
-        let nlines_per_thread = read_buffer(&device, &output_buf, ..);
-        let nlines = nlines_per_thread.iter().fold(0, |acc, e| acc + *e);
-        acc += nlines;
+        let nlines = read_buffer(device, queue, nlines_buf, ..)?[0];
+        acc += u64::from(nlines);
         output_dur += output_timer.elapsed();
 
-        let charpos_output_buf = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("charpos output"),
-            size: ((nlines + 1) * 4) as wgpu::BufferAddress,
-            // Can be read to the CPU, and can be copied from the shader's storage buffer
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::MAP_READ
-                | wgpu::BufferUsages::COPY_SRC
-                | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
+        if nlines == 0 {
+            // The whole chunk is part of a line that continues into the next chunk, so it will
+            // be parsed out of the residual of whichever chunk terminates that line. The producer
+            // only hangs up once it's done, in which case there's nothing left to parse.
+            if free_buffer.send(input_buf_id).is_err() {
+                break;
+            }
+            continue;
+        }
+
+        let scope = context.error_scope();
+        let charpos_output_buf = create_output_buffer(device, "charpos output", nlines * 4);
 
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("get char positions"),
         });
         bind_buffers_and_run(
             &mut encoder,
-            &device,
+            device,
             &getcharpos_gen.compute_pipeline,
             &getcharpos_gen.bind_group_layout,
             &[
                 &input_bufs[input_buf_id],
-                &chunk_size_buf,
-                &data_len_buf,
+                chunk_size_buf,
+                data_len_buf,
                 &char_buf,
-                &nlines_buf,
-                &output_buf,
+                &sums_buf,
+                n_threads_buf,
                 &charpos_output_buf,
+                &quote_buf,
+                in_quotes_buf,
             ],
             dispatch,
         );
 
         // Run the queued computation
         queue.submit(Some(encoder.finish()));
+        scope.finish()?;
 
         device.poll(wgpu::Maintain::Wait);
-        eprintln!("Staring encode for parsecsv");
-
-        let lines_per_thread = std::cmp::max(1, nlines / (dispatch.0 * parsecsv_gen.workgroup_dim.0));
-        store_u32(&queue, &chunk_size_buf, lines_per_thread);
 
-        eprintln!("parsecsv.0");
+        let scope = context.error_scope();
+        let lines_per_thread = nlines.div_ceil(dispatch.0 * parsecsv_gen.workgroup_dim.0);
+        store_u32(queue, chunk_size_buf, lines_per_thread);
 
-        let col0output_buf = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("parsed column0 output"),
-            size: ((nlines + 1) * 4) as wgpu::BufferAddress,
-            // Can be read to the CPU, and can be copied from the shader's storage buffer
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::MAP_READ
-                | wgpu::BufferUsages::COPY_SRC
-                | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
+        // The kernels read the residual a word at a time, see `kernelcodegen::bytes`
+        let residual: Vec<u8> = bytes::pack(&chunk.residual)
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect();
+        let residual_buf = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("residual"),
+            contents: &residual,
+            usage: wgpu::BufferUsages::STORAGE,
         });
+        store_u32(queue, residual_len_buf, chunk.residual.len() as u32);
+
+        // New buffers are zeroed, which the validity and errors outputs rely on
+        let column_output_bufs: Vec<[wgpu::Buffer; 3]> = columns
+            .iter()
+            .map(|column| {
+                let width = parsecsv::type_width(column.ty.kernel_type()) as u32;
+                [
+                    create_output_buffer(
+                        device,
+                        &format!("parsed {} output", column.name),
+                        nlines * 4 * width,
+                    ),
+                    create_output_buffer(
+                        device,
+                        &format!("{} validity", column.name),
+                        nlines.div_ceil(32) * 4,
+                    ),
+                    create_output_buffer(
+                        device,
+                        &format!("{} errors", column.name),
+                        errors_buffer_size() as u32,
+                    ),
+                ]
+            })
+            .collect();
 
-        eprintln!("parsecsv.1");
         let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
             label: Some("parse CSV"),
         });
-        bind_buffers_and_run(
-            &mut encoder,
-            &device,
-            &parsecsv_gen.compute_pipeline,
-            &parsecsv_gen.bind_group_layout,
-            &[
-                &input_bufs[input_buf_id + 1],
-                &data_len_buf,
-                &input_bufs[input_buf_id],
-                &data_len_buf,
-                &delimeter_buf,
-                &chunk_size_buf,
-                &charpos_output_buf,
-                &col0output_buf,
-            ],
-            dispatch,
-        );
-        eprintln!("parsecsv.2");
+        for (
+            [index_buf, type_buf, nullable_buf, format_buf],
+            [parsed_buf, validity_buf, errors_buf],
+        ) in column_bufs.iter().zip(&column_output_bufs)
+        {
+            bind_buffers_and_run(
+                &mut encoder,
+                device,
+                &parsecsv_gen.compute_pipeline,
+                &parsecsv_gen.bind_group_layout,
+                &[
+                    &residual_buf,
+                    residual_len_buf,
+                    &input_bufs[input_buf_id],
+                    data_len_buf,
+                    &delimeter_buf,
+                    chunk_size_buf,
+                    &charpos_output_buf,
+                    parsed_buf,
+                    index_buf,
+                    type_buf,
+                    nullable_buf,
+                    validity_buf,
+                    errors_buf,
+                    &quote_buf,
+                    &strip_cr_buf,
+                    &decimal_buf,
+                    powers_of_five_buf,
+                    format_buf,
+                ],
+                dispatch,
+            );
+        }
+        // Where the totals of the scans of a chunk are, see `kernelcodegen::scan`
+        let total_offset = scan::total_offset(n_threads) as wgpu::BufferAddress * 4;
+        // Dictionary columns are encoded straight after parsecsv, so that only their codes and
+        // distinct strings have to be read back.
+        let dictionary_bufs: Vec<Option<[wgpu::Buffer; 6]>> = columns
+            .iter()
+            .zip(&column_output_bufs)
+            .map(|(column, [parsed_buf, validity_buf, _])| {
+                if column.ty != ColumnType::Dictionary {
+                    return None;
+                }
+                let table_size = dictencode::table_size(nlines);
+                // [table, first rows, counts and the levels of their scan, entries, codes,
+                //  number of entries], see dictencode
+                let bufs = [
+                    ("table", table_size * 4),
+                    ("first rows", table_size * 4),
+                    ("counts", scan::sums_len(n_threads) * 4),
+                    ("entries", nlines * 8),
+                    ("codes", nlines * 4),
+                    ("entry count", 4),
+                ]
+                .map(|(label, size)| {
+                    create_output_buffer(device, &format!("{} {}", column.name, label), size)
+                });
+                let passes = [
+                    (dictencode::PASS_INSERT, dispatch.0),
+                    (dictencode::PASS_COUNT, dispatch.0),
+                ]
+                .into_iter()
+                .chain(scan_passes(dictencode::PASS_SCAN, n_threads))
+                .chain([
+                    (dictencode::PASS_ASSIGN, dispatch.0),
+                    (dictencode::PASS_RESOLVE, dispatch.0),
+                ]);
+                for (pass, n_workgroups) in passes {
+                    bind_buffers_and_run(
+                        &mut encoder,
+                        device,
+                        &dictencode_gen.compute_pipeline,
+                        &dictencode_gen.bind_group_layout,
+                        &[
+                            &residual_buf,
+                            residual_len_buf,
+                            &input_bufs[input_buf_id],
+                            chunk_size_buf,
+                            &dictencode_pass_bufs[pass as usize],
+                            &quote_buf,
+                            parsed_buf,
+                            validity_buf,
+                            &bufs[0],
+                            &bufs[1],
+                            &bufs[2],
+                            n_threads_buf,
+                            &bufs[3],
+                            &bufs[4],
+                        ],
+                        (n_workgroups, 1, 1),
+                    );
+                }
+                encoder.copy_buffer_to_buffer(&bufs[2], total_offset, &bufs[5], 0, 4);
+                Some(bufs)
+            })
+            .collect();
+
+        // The strings to gather for each column are those of every row of a string column, or
+        // the distinct strings of a dictionary column. Their offsets are a scan of their lengths,
+        // whose total is copied out to size the values buffer, see gatherstrings.
+        let string_bufs: Vec<Option<[wgpu::Buffer; 3]>> = columns
+            .iter()
+            .zip(&column_output_bufs)
+            .zip(&dictionary_bufs)
+            .map(|((column, [parsed_buf, _, _]), dictionary)| {
+                let (pairs_buf, n_strings_buf) = match dictionary {
+                    Some(bufs) => (&bufs[3], &bufs[5]),
+                    None if column.ty == ColumnType::Utf8 => (parsed_buf, nlines_buf),
+                    None => return None,
+                };
+                // [offsets, lengths and the levels of their scan, total length]
+                let bufs = [
+                    ("offsets", (nlines + 1) * 4),
+                    ("string lengths", scan::sums_len(n_threads) * 4),
+                    ("values length", 4),
+                ]
+                .map(|(label, size)| {
+                    create_output_buffer(device, &format!("{} {}", column.name, label), size)
+                });
+                let passes = std::iter::once((gatherstrings::PASS_COUNT, dispatch.0))
+                    .chain(scan_passes(gatherstrings::PASS_SCAN, n_threads))
+                    .chain(std::iter::once((gatherstrings::PASS_OFFSETS, dispatch.0)));
+                for (pass, n_workgroups) in passes {
+                    bind_buffers_and_run(
+                        &mut encoder,
+                        device,
+                        &gatherstrings_gen.compute_pipeline,
+                        &gatherstrings_gen.bind_group_layout,
+                        &[
+                            &residual_buf,
+                            residual_len_buf,
+                            &input_bufs[input_buf_id],
+                            chunk_size_buf,
+                            pairs_buf,
+                            &bufs[0],
+                            placeholder_buf,
+                            &quote_buf,
+                            &gatherstrings_pass_bufs[pass as usize],
+                            n_strings_buf,
+                            &bufs[1],
+                            n_threads_buf,
+                        ],
+                        (n_workgroups, 1, 1),
+                    );
+                }
+                encoder.copy_buffer_to_buffer(&bufs[1], total_offset, &bufs[2], 0, 4);
+                Some(bufs)
+            })
+            .collect();
 
         // Run the queued computation
         queue.submit(Some(encoder.finish()));
+        // Whatever parsecsv, dictencode or gatherstrings wrote is garbage if they couldn't run
+        scope.finish()?;
 
-        eprintln!("parsecsv.3");
-        let x = read_buffer(&device, &col0output_buf, ..);
-        for el in x {
-            println!("{}", el);
-        }
-        eprintln!("parsecsv.4");
+        // parsecsv only locates the strings, which are then compacted into one values buffer per
+        // column at the offsets computed above.
+        let scope = context.error_scope();
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("gather strings"),
+        });
+        let string_bufs: Vec<Option<(u32, wgpu::Buffer, wgpu::Buffer)>> = columns
+            .iter()
+            .zip(&column_output_bufs)
+            .zip(dictionary_bufs.iter().zip(string_bufs))
+            .map(
+                |((column, [parsed_buf, _, _]), (dictionary, string_bufs))| {
+                    let Some([offsets_buf, lengths_buf, values_len_buf]) = string_bufs else {
+                        return Ok(None);
+                    };
+                    let (pairs_buf, n_strings_buf) = match dictionary {
+                        Some(bufs) => (&bufs[3], &bufs[5]),
+                        None => (parsed_buf, nlines_buf),
+                    };
+                    let values_len = read_buffer(device, queue, &values_len_buf, ..)?[0];
+                    let values_buf = create_output_buffer(
+                        device,
+                        &format!("{} values", column.name),
+                        std::cmp::max(values_len.next_multiple_of(4), 4),
+                    );
+                    bind_buffers_and_run(
+                        &mut encoder,
+                        device,
+                        &gatherstrings_gen.compute_pipeline,
+                        &gatherstrings_gen.bind_group_layout,
+                        &[
+                            &residual_buf,
+                            residual_len_buf,
+                            &input_bufs[input_buf_id],
+                            chunk_size_buf,
+                            pairs_buf,
+                            &offsets_buf,
+                            &values_buf,
+                            &quote_buf,
+                            &gatherstrings_pass_bufs[gatherstrings::PASS_GATHER as usize],
+                            n_strings_buf,
+                            &lengths_buf,
+                            n_threads_buf,
+                        ],
+                        dispatch,
+                    );
+                    Ok(Some((values_len, offsets_buf, values_buf)))
+                },
+            )
+            .collect::<Result<_, BufferAsyncError>>()?;
+        queue.submit(Some(encoder.finish()));
+        scope.finish()?;
 
-        let _ = compute_pbar.update(data_len as usize);
+        let mut output = PipelineOutput::new(columns);
+        output.nlines = nlines.into();
+        output.inputs.push((chunk.input, nlines.into()));
+        for ((parsed, [parsed_buf, validity_buf, errors_buf]), (dictionary, string_bufs)) in output
+            .columns
+            .iter_mut()
+            .zip(&column_output_bufs)
+            .zip(dictionary_bufs.iter().zip(string_bufs))
+        {
+            let words = match dictionary {
+                Some(bufs) => read_buffer(device, queue, &bufs[4], ..)?,
+                None if parsed.ty == ColumnType::Utf8 => Vec::new(),
+                None => read_buffer(device, queue, parsed_buf, ..)?,
+            };
+            let (offsets, values) = match string_bufs {
+                Some((values_len, offsets_buf, values_buf)) => {
+                    let n_strings = match dictionary {
+                        Some(bufs) => read_buffer(device, queue, &bufs[5], ..)?[0],
+                        None => nlines,
+                    };
+                    // Only the offsets of the distinct strings of a dictionary are meaningful
+                    let offsets_len = (u64::from(n_strings) + 1) * 4;
+                    let offsets = read_buffer(device, queue, &offsets_buf, ..offsets_len)?;
+                    let mut values: Vec<u8> = read_buffer(device, queue, &values_buf, ..)?
+                        .iter()
+                        .flat_map(|w| w.to_le_bytes())
+                        .collect();
+                    values.truncate(values_len as usize);
+                    (Some(offsets), Some(values))
+                }
+                None => (None, None),
+            };
+            parsed.append_chunk(
+                &words,
+                offsets.as_deref().zip(values),
+                &read_buffer(device, queue, validity_buf, ..)?,
+                &read_buffer(device, queue, errors_buf, ..)?,
+                nlines,
+                acc - u64::from(nlines),
+            );
+        }
+        // The copies made to read the outputs back aren't scoped, so their errors are only caught
+        // by the device's handler
+        context.check_errors()?;
+        sink.push_chunk(output)?;
 
-        if end == total_len {
+        // Mark the input buffer as ready for writing again
+        if free_buffer.send(input_buf_id).is_err() {
             break;
         }
-        // Mark the input buffer as ready for writing again
-        free_buffer
-            .send(input_buf_id)
-            .expect("semaphore add failed");
     }
-    drop(compute_pbar);
-
-    eprintln!("setup_dur: {:?}", setup_dur);
-    eprintln!("wait_dur: {:?}", setup_dur);
-    eprintln!("write_uniform_dur: {:?}", encoder_dur);
-    eprintln!("encoder_dur: {:?}", encoder_dur);
-    eprintln!("submit_dur: {:?}", submit_dur);
-    eprintln!("output_dur: {:?}", output_dur);
-    eprintln!("max_chunk_size: {:?}", max_chunk_size);
+    log::debug!(
+        "setup {:?}, waiting for input {:?}, writing uniforms {:?}, encoding {:?}, \
+         submitting {:?}, reading back {:?}, max chunk size {}",
+        setup_dur,
+        wait_dur,
+        write_uniform_dur,
+        encoder_dur,
+        submit_dur,
+        output_dur,
+        max_chunk_size
+    );
 
-    acc
+    Ok(sink)
 }
 
-pub async fn run_charcount_shader(input: &[u8], char: u8) -> Result<u32, BufferAsyncError> {
-    let total_len = input.len();
-
-    let adapter = init_adapter().await.expect("Failed to get adapter");
-    let (device, queue) = init_device(&adapter)
-        .await
-        .expect("Failed to create device");
-    let device = std::sync::Arc::new(device);
-
-    let limits = device.limits();
-    // eprintln!("LIMITS = {:?}", limits);
-    // Using a smaller size here seems to have better performance. Maybe because it provides more
-    // opportunities for compute to overlap with IO, hiding the latency?
-    let max_buffer_size = limits.max_storage_buffer_binding_size / 8;
-    println!("max_buffer_size {}", max_buffer_size);
-    const N_INPUT_BUFS: usize = 8;
-    let mut input_bufs = Vec::new();
-    for i in 0..N_INPUT_BUFS {
-        input_bufs.push(device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some(&format!("File Input {}", i)),
-            size: max_buffer_size as wgpu::BufferAddress,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::MAP_WRITE
-                | wgpu::BufferUsages::COPY_SRC
-                | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        }));
-    }
-    let input_bufs = std::sync::Arc::new(input_bufs);
+/// Streams `inputs` through the GPU in chunks as they're read, one after the other, parsing every
+/// line as described by `config` and handing the output of each chunk to `sink`. A chunk never
+/// spans two inputs, and the last line of each input ends with it. Returns the sink once every
+/// input has been read to the end and every chunk has been pushed. If the consumer thread fails,
+/// reading stops and its error is returned.
+pub(crate) async fn run_pipeline<I, R, S>(
+    context: &Arc<GpuContext>,
+    inputs: I,
+    config: &PipelineConfig,
+    sink: S,
+) -> Result<S, NvParseError>
+where
+    I: IntoIterator<Item = Result<R, NvParseError>>,
+    R: BufRead,
+    S: ChunkSink,
+{
+    let device = context.device();
+    // The input buffers and uniforms are only written by one pipeline at a time, so other
+    // sessions sharing the context use another set of them while this one runs
+    let scratch = Arc::new(context.take_scratch());
+    // The input is read into the staging buffers instead, if the device can't map input buffers
+    let input_bufs = scratch.staging.as_ref().unwrap_or(&scratch.inputs);
+    let max_buffer_size = scratch.input_size;
 
     // This channel marks input buffs in the vector above as "free" for writing or "allocated" for
     // compute. A producer will need to allocate buffers and transfer them to the consumer, which
@@ -390,59 +1236,208 @@ pub async fn run_charcount_shader(input: &[u8], char: u8) -> Result<u32, BufferA
 
     // Takes filled in buffers and run the compute kernel on the GPU
     let consumer = {
-        let input_bufs = input_bufs.clone();
-        let device = device.clone();
-        thread::spawn(move || -> u32 {
+        let context = context.clone();
+        let scratch = scratch.clone();
+        let config = config.clone();
+        thread::spawn(move || -> Result<S, NvParseError> {
             let timer = std::time::Instant::now();
-            let res = consume_buffer(
-                total_len,
-                device,
-                &queue,
-                &input_bufs,
-                char,
-                receiver,
-                free_buffer,
-            );
-            eprintln!("GPU time: {:?} (res={})", timer.elapsed(), res);
+            let res = consume_buffer(&context, &scratch, &config, receiver, free_buffer, sink);
+            log::debug!("GPU time: {:?}", timer.elapsed());
             res
         })
     };
 
     let mut write_time = std::time::Duration::ZERO;
 
-    // Copy chunks into buffers that aren't currently in-use
-    let mut offset = 0;
-    while offset < total_len {
-        // Get a buffer that is not in use
-        let input_buf_id = allocate_buffer.recv().unwrap();
-        let end = std::cmp::min(offset + max_buffer_size as usize, total_len);
-        let slice = &input[offset..end];
+    // Read chunks into buffers that aren't currently in-use, until the inputs run out or fail
+    let produced: Result<(), NvParseError> = async {
+        for (index, input) in inputs.into_iter().enumerate() {
+            let mut input = input?;
+            // Lines don't continue from one input into the next
+            let mut stitcher = LineStitcher::new(config.char, config.quote);
+            // An empty input has no line to terminate
+            let mut last = input.fill_buf()?.is_empty();
+            while !last {
+                // Get a buffer that is not in use. The consumer only hangs up if it failed, in
+                // which case its error is returned below.
+                let Ok(input_buf_id) = allocate_buffer.recv() else {
+                    return Ok(());
+                };
 
-        let timer = std::time::Instant::now();
-        let input_buf = &input_bufs[input_buf_id];
-        // Map the input buffer into memory to avoid intermediate copying
-        let (resolver, waiter) = oneshot::channel();
-        let input_slice = input_buf.slice(0..(slice.len() as u64));
-        input_slice.map_async(wgpu::MapMode::Write, move |res| {
-            resolver.send(res).unwrap();
-        });
-        // Wait for the buffer to be mapped and ready for writing
-        device.poll(wgpu::Maintain::Wait);
-        waiter.await.unwrap().expect("mapping input buffer failed");
-        input_slice.get_mapped_range_mut().clone_from_slice(slice);
-        // Unmap the GPU buffer so that it can be used in the shader
-        input_buf.unmap();
-        write_time += timer.elapsed();
+                let timer = std::time::Instant::now();
+                let input_buf = &input_bufs[input_buf_id];
+                // Map the input buffer into memory, and read straight into it to avoid
+                // intermediate copying
+                let (resolver, waiter) = oneshot::channel();
+                let input_slice = input_buf.slice(..);
+                let scope = context.error_scope();
+                input_slice.map_async(wgpu::MapMode::Write, move |res| {
+                    let _ = resolver.send(res);
+                });
+                scope.finish()?;
+                // Wait for the buffer to be mapped and ready for writing
+                device.poll(wgpu::Maintain::Wait);
+                waiter.await.unwrap_or(Err(BufferAsyncError))?;
+                let mut mapped = input_slice.get_mapped_range_mut();
+                let in_quotes = stitcher.in_quotes();
+                let read = (|| -> Result<_, NvParseError> {
+                    // Leave room in the buffer to terminate the final line
+                    let read_len =
+                        read_chunk(&mut input, &mut mapped[..(max_buffer_size as usize - 1)])?;
+                    let last = input.fill_buf()?.is_empty();
+                    let slice = &mapped[..read_len];
+                    let terminate = stitcher.needs_terminator(slice, last);
+                    let residual = stitcher.next_residual(slice, last)?;
+                    if terminate {
+                        mapped[read_len] = config.char;
+                    }
+                    Ok((read_len + terminate as usize, residual, last))
+                })();
+                drop(mapped);
+                // Unmap the GPU buffer so that it can be used in the shader, or by the next
+                // pipeline if reading failed
+                let scope = context.error_scope();
+                input_buf.unmap();
+                scope.finish()?;
+                let (len, residual, input_done) = read?;
+                last = input_done;
+                write_time += timer.elapsed();
 
-        sender
-            .send((offset, end, input_buf_id))
-            .expect("send failed");
+                let chunk = Chunk {
+                    input_buf_id,
+                    len,
+                    residual,
+                    in_quotes,
+                    input: index as u32,
+                };
+                if sender.send(chunk).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    // Hanging up tells the consumer that there are no more chunks
+    drop(sender);
 
-        offset = end;
+    log::debug!("write time: {:?}", write_time);
+
+    let consumed = match consumer.join() {
+        Ok(consumed) => consumed,
+        Err(payload) => Err(NvParseError::from_panic(payload)),
+    };
+    // The consumer's handle on the scratch buffers is gone once it has been joined, even if it
+    // failed. Should one still be around, the context makes new buffers for the next pipeline.
+    if let Some(scratch) = Arc::into_inner(scratch) {
+        context.return_scratch(scratch);
     }
+    // The producer stops early when the consumer fails, so the consumer's error comes first
+    let output = consumed?;
+    produced?;
+    Ok(output)
+}
 
-    eprintln!("write time: {:?}", write_time);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let acc = consumer.join().expect("Thread failed");
-    Ok(acc)
+    /// The residual of each chunk, and whether the chunk after it starts inside quotes. If `end`
+    /// is set, the last chunk ends the input.
+    fn residuals(chunks: &[&[u8]], quote: Option<u8>, end: bool) -> Vec<(Vec<u8>, bool)> {
+        let mut stitcher = LineStitcher::new(b'\n', quote);
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| {
+                let last = end && i == chunks.len() - 1;
+                let residual = stitcher.next_residual(chunk, last).unwrap();
+                (residual, stitcher.in_quotes())
+            })
+            .collect()
+    }
+
+    fn unquoted(residual: &[u8]) -> (Vec<u8>, bool) {
+        (residual.to_vec(), false)
+    }
+
+    #[test]
+    fn stitches_lines_across_chunks() {
+        let chunks: [&[u8]; 3] = [b"a|1\nb|", b"2\nc|3\nd", b"\n"];
+        let expected = [unquoted(b"a|1\n"), unquoted(b"b|2\n"), unquoted(b"d\n")];
+        assert_eq!(residuals(&chunks, None, false), expected);
+    }
+
+    #[test]
+    fn carries_chunks_without_terminators() {
+        let chunks: [&[u8]; 4] = [b"ab", b"cd", b"e\nf\n", b"g"];
+        let expected = [
+            unquoted(b""),
+            unquoted(b""),
+            unquoted(b"abcde\n"),
+            unquoted(b""),
+        ];
+        assert_eq!(residuals(&chunks, None, false), expected);
+    }
+
+    #[test]
+    fn terminates_the_last_line() {
+        // The partial line is carried over into the chunk that ends the input
+        let chunks: [&[u8]; 2] = [b"1|a\n2|", b"b"];
+        let expected = [unquoted(b"1|a\n"), unquoted(b"2|b\n")];
+        assert_eq!(residuals(&chunks, None, true), expected);
+        // A last chunk that has a terminator parses its last line itself, once one is appended
+        let chunks: [&[u8]; 2] = [b"1|a\n2|", b"b\n3|c"];
+        let expected = [unquoted(b"1|a\n"), unquoted(b"2|b\n")];
+        assert_eq!(residuals(&chunks, None, true), expected);
+        // An empty last chunk terminates the line carried into it
+        let chunks: [&[u8]; 2] = [b"1|a\n2|b", b""];
+        let expected = [unquoted(b"1|a\n"), unquoted(b"2|b\n")];
+        assert_eq!(residuals(&chunks, None, true), expected);
+    }
+
+    #[test]
+    fn skips_quoted_terminators() {
+        let chunks: [&[u8]; 3] = [b"1|\"x\ny\"\n2|\"z", b"\nw\"\n3|\"", b"q\"\n"];
+        let expected = [
+            (b"1|\"x\ny\"\n".to_vec(), true),
+            (b"2|\"z\nw\"\n".to_vec(), true),
+            (b"3|\"q\"\n".to_vec(), false),
+        ];
+        assert_eq!(residuals(&chunks, Some(b'"'), false), expected);
+        // Without quoting, the quotes are just characters
+        let expected = [
+            unquoted(b"1|\"x\n"),
+            unquoted(b"2|\"z\n"),
+            unquoted(b"3|\"q\"\n"),
+        ];
+        assert_eq!(residuals(&chunks, None, false), expected);
+    }
+
+    #[test]
+    fn carries_quoted_fields_spanning_chunks() {
+        let chunks: [&[u8]; 3] = [b"1|\"a\n", b"b\nc", b"\"\n2|d\n"];
+        let expected = [
+            (b"".to_vec(), true),
+            (b"".to_vec(), true),
+            (b"1|\"a\nb\nc\"\n".to_vec(), false),
+        ];
+        assert_eq!(residuals(&chunks, Some(b'"'), false), expected);
+    }
+
+    #[test]
+    fn rejects_unterminated_quotes() {
+        // The quote is still open at the end of the input, with or without a final terminator
+        let mut stitcher = LineStitcher::new(b'\n', Some(b'"'));
+        let residual = stitcher.next_residual(b"1|\"a\n2|b\n", true);
+        assert!(matches!(residual, Err(NvParseError::MalformedInput(_))));
+        let mut stitcher = LineStitcher::new(b'\n', Some(b'"'));
+        stitcher.next_residual(b"1|\"a\n", false).unwrap();
+        let residual = stitcher.next_residual(b"2|b", true);
+        assert!(matches!(residual, Err(NvParseError::MalformedInput(_))));
+        // Without quoting, the same input is fine
+        let mut stitcher = LineStitcher::new(b'\n', None);
+        let residual = stitcher.next_residual(b"1|\"a\n2|b\n", true).unwrap();
+        assert_eq!(residual, b"1|\"a\n");
+    }
 }
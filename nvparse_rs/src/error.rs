@@ -0,0 +1,187 @@
+use std::any::Any;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The field is not a valid value of the column's type
+    Invalid,
+    /// The field is well formed, but its value can't be represented by the column's type
+    OutOfRange,
+    /// The line has too few fields
+    MissingField,
+    /// The field is empty, but the column isn't nullable
+    Empty,
+}
+
+impl ParseErrorKind {
+    /// Decodes an error kind recorded by the parsecsv kernel. Any other kind means the errors
+    /// buffer that it was read from is corrupt.
+    pub(crate) fn from_kernel(kind: u32) -> Result<Self, NvParseError> {
+        match kind {
+            parsecsv::ERROR_INVALID => Ok(ParseErrorKind::Invalid),
+            parsecsv::ERROR_OUT_OF_RANGE => Ok(ParseErrorKind::OutOfRange),
+            parsecsv::ERROR_MISSING_FIELD => Ok(ParseErrorKind::MissingField),
+            parsecsv::ERROR_EMPTY => Ok(ParseErrorKind::Empty),
+            _ => Err(NvParseError::CorruptOutput(format!(
+                "unknown parse error kind {}",
+                kind
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ParseErrorKind::Invalid => "invalid value",
+            ParseErrorKind::OutOfRange => "value out of range",
+            ParseErrorKind::MissingField => "missing field",
+            ParseErrorKind::Empty => "empty field in non-nullable column",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// A field that could not be parsed. The row of the field is null in the output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Row of the output that the field belongs to
+    pub line: u64,
+    /// Name of the column in the schema
+    pub column: String,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    /// Converts the (line, kind) pairs recorded by parsecsv for the column named `column`
+    pub(crate) fn from_kernel(
+        column: &str,
+        errors: Vec<(u64, u32)>,
+    ) -> Result<Vec<ParseError>, NvParseError> {
+        errors
+            .into_iter()
+            .map(|(line, kind)| {
+                Ok(ParseError {
+                    line,
+                    column: column.to_owned(),
+                    kind: ParseErrorKind::from_kernel(kind)?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Why an input couldn't be parsed as a whole, as opposed to a single field of it (see
+/// `ParseError`).
+#[derive(Debug)]
+pub enum NvParseError {
+    /// No adapter is available to parse on
+    NoAdapter,
+    /// The adapter doesn't support these features, which the kernels need
+    MissingFeatures(wgpu::Features),
+    /// The adapter couldn't create a device with the features and limits the kernels need
+    RequestDevice(wgpu::RequestDeviceError),
+    /// A buffer couldn't be mapped, to write the input into it or to read the output back
+    BufferMap(wgpu::BufferAsyncError),
+    /// wgpu rejected a kernel, or a dispatch of one
+    Validation(String),
+    /// Reading the input failed
+    Io(io::Error),
+    /// The schema can't be parsed with, e.g. its dialect uses the same character for two purposes
+    InvalidSchema(String),
+    /// The input doesn't match the schema, e.g. a column is selected by a name that isn't in
+    /// the header
+    MalformedInput(String),
+    /// A kernel's output can't be decoded, e.g. parsecsv recorded an unknown error kind
+    CorruptOutput(String),
+    /// A thread of the pipeline panicked
+    Panic(String),
+}
+
+impl NvParseError {
+    /// Converts the payload of a thread that panicked
+    pub fn from_panic(payload: Box<dyn Any + Send>) -> Self {
+        let msg = match payload.downcast::<String>() {
+            Ok(msg) => *msg,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(msg) => msg.to_string(),
+                Err(_) => "unknown panic".to_string(),
+            },
+        };
+        NvParseError::Panic(msg)
+    }
+}
+
+impl fmt::Display for NvParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NvParseError::NoAdapter => write!(f, "no adapter available"),
+            NvParseError::MissingFeatures(features) => {
+                write!(f, "adapter is missing features {:?}", features)
+            }
+            NvParseError::RequestDevice(e) => write!(f, "failed to request device: {}", e),
+            NvParseError::BufferMap(e) => write!(f, "failed to map buffer: {}", e),
+            NvParseError::Validation(msg) => write!(f, "kernel validation failed: {}", msg),
+            NvParseError::Io(e) => write!(f, "failed to read input: {}", e),
+            NvParseError::InvalidSchema(msg) => write!(f, "invalid schema: {}", msg),
+            NvParseError::MalformedInput(msg) => write!(f, "{}", msg),
+            NvParseError::CorruptOutput(msg) => write!(f, "corrupt kernel output: {}", msg),
+            NvParseError::Panic(msg) => write!(f, "pipeline thread panicked: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NvParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NvParseError::RequestDevice(e) => Some(e),
+            NvParseError::BufferMap(e) => Some(e),
+            NvParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<wgpu::RequestDeviceError> for NvParseError {
+    fn from(e: wgpu::RequestDeviceError) -> Self {
+        NvParseError::RequestDevice(e)
+    }
+}
+
+impl From<wgpu::BufferAsyncError> for NvParseError {
+    fn from(e: wgpu::BufferAsyncError) -> Self {
+        NvParseError::BufferMap(e)
+    }
+}
+
+impl From<wgpu::Error> for NvParseError {
+    fn from(e: wgpu::Error) -> Self {
+        NvParseError::Validation(e.to_string())
+    }
+}
+
+impl From<io::Error> for NvParseError {
+    fn from(e: io::Error) -> Self {
+        NvParseError::Io(e)
+    }
+}
+
+/// Arrow only rejects the arrays built from a chunk if the kernels' output is inconsistent
+impl From<arrow::error::ArrowError> for NvParseError {
+    fn from(e: arrow::error::ArrowError) -> Self {
+        NvParseError::CorruptOutput(e.to_string())
+    }
+}
@@ -0,0 +1,407 @@
+//! Reads the header line of the input on the CPU, and resolves the columns of a schema against
+//! it. The header is cut off the input before it's handed to the GPU.
+use std::io::{self, BufRead, Cursor, Read};
+
+use kernelcodegen::bytes;
+
+use crate::error::NvParseError;
+use crate::schema::{Column, Dialect, Header, Schema};
+
+/// Finds the first line of `input`. Returns the end of its fields, which excludes the terminator
+/// (and a `\r` before it, if the dialect strips them), and the start of the next line.
+fn first_line(input: &[u8], dialect: &Dialect) -> (usize, usize) {
+    let terminator = dialect.terminator.byte();
+    let mut in_quotes = false;
+    let mut end = input.len();
+    for (i, c) in input.iter().enumerate() {
+        if Some(*c) == dialect.quote {
+            in_quotes = !in_quotes;
+        } else if *c == terminator && !in_quotes {
+            end = i;
+            break;
+        }
+    }
+    let next = std::cmp::min(end + 1, input.len());
+    if dialect.terminator.strips_cr() && end > 0 && input[end - 1] == b'\r' {
+        end -= 1;
+    }
+    (end, next)
+}
+
+/// Removes the quotes around a quoted field, and unescapes the quotes inside of it
+fn unquote(field: &[u8], quote: Option<u8>) -> Vec<u8> {
+    let Some(quote) = quote else {
+        return field.to_vec();
+    };
+    if field.len() < 2 || field[0] != quote || field[field.len() - 1] != quote {
+        return field.to_vec();
+    }
+    let mut unquoted = Vec::new();
+    let mut escaped = false;
+    for c in &field[1..(field.len() - 1)] {
+        if *c == quote && !escaped {
+            escaped = true;
+            continue;
+        }
+        escaped = false;
+        unquoted.push(*c);
+    }
+    unquoted
+}
+
+/// Splits `line` into its fields
+fn split_fields(line: &[u8], dialect: &Dialect) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut in_quotes = false;
+    let mut start = 0;
+    for (i, c) in line.iter().enumerate() {
+        if Some(*c) == dialect.quote {
+            in_quotes = !in_quotes;
+        } else if *c == dialect.delimiter && !in_quotes {
+            fields.push(&line[start..i]);
+            start = i + 1;
+        }
+    }
+    fields.push(&line[start..]);
+    fields
+        .into_iter()
+        .map(|field| String::from_utf8_lossy(&unquote(field, dialect.quote)).into_owned())
+        .collect()
+}
+
+/// Guesses whether the first line of `input` is a header. It is if it contains the name of a
+/// column that is selected by name, or if a column that's selected by index fails to parse on
+/// the first line but parses on the second.
+pub(crate) fn detect(input: &[u8], schema: &Schema) -> bool {
+    let dialect = &schema.dialect;
+    let (first_end, second_start) = first_line(input, dialect);
+    let names = split_fields(&input[..first_end], dialect);
+    let (second_end, _) = first_line(&input[second_start..], dialect);
+    let second_end = second_start + second_end;
+
+    let words = bytes::pack(input);
+    let parses = |start: usize, end: usize, index: usize, column: &Column| {
+        let (_, _, status) = parsecsv::parse_field(
+            &words,
+            start,
+            end,
+            u32::from(dialect.delimiter),
+            u32::from(dialect.quote.unwrap_or(0)),
+            index as u32,
+            column.ty.kernel_type(),
+            column.nullable,
+            u32::from(dialect.decimal),
+            &parsecsv::POWERS_OF_FIVE,
+            dialect.datetime_format(column.ty).items(),
+        );
+        status == parsecsv::STATUS_OK
+    };
+    schema.columns.iter().any(|column| match column.index {
+        None => names.contains(&column.name),
+        Some(index) => {
+            second_start < input.len()
+                && !parses(0, first_end, index, column)
+                && parses(second_start, second_end, index, column)
+        }
+    })
+}
+
+/// Reads as many lines off the front of `reader` as `resolve` needs to find the header: none if
+/// the schema says there isn't one, the first line if there is, and the first two lines if it has
+/// to be detected. The rest of the input is left in `reader`.
+fn read_prefix<R: BufRead>(reader: &mut R, schema: &Schema) -> io::Result<Vec<u8>> {
+    let lines = match schema.dialect.header {
+        Header::Absent => 0,
+        Header::Present => 1,
+        Header::Detect => 2,
+    };
+    read_lines(reader, &schema.dialect, lines)
+}
+
+/// Reads the first `lines` lines off the front of `reader`, or all of it if it has fewer
+fn read_lines<R: BufRead>(
+    reader: &mut R,
+    dialect: &Dialect,
+    mut lines: usize,
+) -> io::Result<Vec<u8>> {
+    let terminator = dialect.terminator.byte();
+    let mut in_quotes = false;
+    let mut prefix = Vec::new();
+    while lines > 0 {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            break;
+        }
+        let mut len = buf.len();
+        for (i, c) in buf.iter().enumerate() {
+            if Some(*c) == dialect.quote {
+                in_quotes = !in_quotes;
+            } else if *c == terminator && !in_quotes {
+                lines -= 1;
+                if lines == 0 {
+                    len = i + 1;
+                    break;
+                }
+            }
+        }
+        prefix.extend_from_slice(&buf[..len]);
+        reader.consume(len);
+    }
+    Ok(prefix)
+}
+
+/// The outcome of reading the header of an input
+pub(crate) struct Resolved<'a> {
+    /// `schema` with the index and name of every column filled in
+    pub schema: Schema,
+    /// The fields of the header line, if the input has one
+    pub header: Option<Vec<String>>,
+    /// The input following the header
+    pub input: &'a [u8],
+}
+
+/// Reads the header of `input` if `schema` says it has one, and works out the index of every
+/// column that's selected by name, and the name of every column that's selected by index.
+pub(crate) fn resolve<'a>(input: &'a [u8], schema: &Schema) -> Result<Resolved<'a>, String> {
+    let has_header = match schema.dialect.header {
+        Header::Absent => false,
+        Header::Present => true,
+        Header::Detect => detect(input, schema),
+    };
+
+    let (header, input) = if has_header {
+        let (end, next) = first_line(input, &schema.dialect);
+        (
+            Some(split_fields(&input[..end], &schema.dialect)),
+            &input[next..],
+        )
+    } else {
+        (None, input)
+    };
+
+    let mut schema = schema.clone();
+    for column in &mut schema.columns {
+        match (column.index, &header) {
+            (None, None) => {
+                return Err(format!(
+                    "Column {} is selected by name, but the input has no header",
+                    column.name
+                ))
+            }
+            (None, Some(names)) => {
+                let index = names
+                    .iter()
+                    .position(|name| *name == column.name)
+                    .ok_or_else(|| format!("No column named {} in the header", column.name))?;
+                column.index = Some(index);
+            }
+            (Some(index), header) if column.name.is_empty() => {
+                column.name = header
+                    .as_ref()
+                    .and_then(|names| names.get(index).cloned())
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or_else(|| format!("col{}", index));
+            }
+            (Some(_), _) => {}
+        }
+    }
+
+    Ok(Resolved {
+        schema,
+        header,
+        input,
+    })
+}
+
+/// The outcome of reading the header of the first of several inputs
+pub(crate) struct ResolvedInputs<'a> {
+    /// `schema` with the index and name of every column filled in
+    pub schema: Schema,
+    /// The fields of the header line, if the inputs have one
+    pub header: Option<Vec<String>>,
+    /// Each of the inputs following its header
+    pub inputs: Box<dyn Iterator<Item = Result<Box<dyn BufRead + 'a>, NvParseError>> + 'a>,
+}
+
+/// Reads the header of the first of `inputs` and resolves `schema` against it, like `resolve`.
+/// If the first input has a header, every other input has to start with the same header, which is
+/// cut off. An input with a different header fails with `MalformedInput` once it's reached.
+/// Fails without reading anything if the schema's dialect is invalid, see `Dialect::validate`.
+pub(crate) fn resolve_inputs<'a, I, R>(
+    inputs: I,
+    schema: &Schema,
+) -> Result<ResolvedInputs<'a>, NvParseError>
+where
+    I: IntoIterator<Item = io::Result<R>>,
+    I::IntoIter: 'a,
+    R: BufRead + 'a,
+{
+    schema
+        .dialect
+        .validate()
+        .map_err(NvParseError::InvalidSchema)?;
+    let mut inputs = inputs.into_iter();
+    let mut first = inputs.next().transpose()?;
+    let prefix = match &mut first {
+        Some(first) => read_prefix(first, schema)?,
+        None => Vec::new(),
+    };
+    let Resolved {
+        schema,
+        header,
+        input,
+    } = resolve(&prefix, schema).map_err(NvParseError::MalformedInput)?;
+
+    // The part of the prefix following the header is read before the rest of the first input
+    let header_len = prefix.len() - input.len();
+    let mut rest = Cursor::new(prefix);
+    rest.set_position(header_len as u64);
+    let first = first.map(|first| Ok(Box::new(rest.chain(first)) as Box<dyn BufRead>));
+    let dialect = schema.dialect.clone();
+    let expected = header.clone();
+    let others = inputs.enumerate().map(move |(index, input)| {
+        let mut input = input?;
+        if let Some(expected) = &expected {
+            let line = read_lines(&mut input, &dialect, 1)?;
+            let (end, _) = first_line(&line, &dialect);
+            let names = split_fields(&line[..end], &dialect);
+            // An empty input has no header to compare
+            if !line.is_empty() && names != *expected {
+                return Err(NvParseError::MalformedInput(format!(
+                    "The header of input {} is {:?}, but the header of the first input is {:?}",
+                    index + 2,
+                    names,
+                    expected
+                )));
+            }
+        }
+        Ok(Box::new(input) as Box<dyn BufRead>)
+    });
+
+    Ok(ResolvedInputs {
+        schema,
+        header,
+        inputs: Box::new(first.into_iter().chain(others)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::{ColumnType, Terminator};
+
+    fn schema(columns: Vec<Column>, header: Header) -> Schema {
+        Schema::new(columns).dialect(Dialect::rfc4180().header(header))
+    }
+
+    /// Resolves `schema` against `inputs`, and reads the inputs following their headers
+    fn read_inputs(inputs: &[&str], schema: &Schema) -> Result<(Schema, Vec<u8>), NvParseError> {
+        let inputs = inputs.iter().map(|input| Ok(Cursor::new(input.as_bytes())));
+        let resolved = resolve_inputs(inputs, schema)?;
+        let mut data = Vec::new();
+        for input in resolved.inputs {
+            input?.read_to_end(&mut data)?;
+        }
+        Ok((resolved.schema, data))
+    }
+
+    #[test]
+    fn first_line_skips_quoted_terminators() {
+        let dialect = Dialect::rfc4180();
+        assert_eq!(first_line(b"a,\"b\nc\"\r\nd", &dialect), (7, 9));
+        assert_eq!(first_line(b"a,b", &dialect), (3, 3));
+        let lf = dialect.terminator(Terminator::Lf);
+        assert_eq!(first_line(b"a,b\r\nc", &lf), (4, 5));
+    }
+
+    #[test]
+    fn splits_quoted_fields() {
+        let dialect = Dialect::rfc4180();
+        let fields = split_fields(b"id,\"first, last\",\"say \"\"hi\"\"\",", &dialect);
+        assert_eq!(fields, ["id", "first, last", "say \"hi\"", ""]);
+    }
+
+    #[test]
+    fn detects_headers() {
+        let by_name = schema(vec![Column::named("id", ColumnType::U32)], Header::Detect);
+        assert!(detect(b"id,name\n1,a\n", &by_name));
+        assert!(!detect(b"1,a\n2,b\n", &by_name));
+        let by_index = schema(vec![Column::at(0, ColumnType::U32)], Header::Detect);
+        assert!(detect(b"id,name\n1,a\n", &by_index));
+        assert!(!detect(b"1,a\n2,b\n", &by_index));
+        // Strings parse either way, so there's nothing to tell a header apart by
+        let strings = schema(vec![Column::at(1, ColumnType::Utf8)], Header::Detect);
+        assert!(!detect(b"id,name\n1,a\n", &strings));
+    }
+
+    #[test]
+    fn resolves_columns() {
+        let columns = vec![
+            Column::named("name", ColumnType::Utf8),
+            Column::at(0, ColumnType::U32),
+        ];
+        let resolved = resolve(b"id,name\r\n1,a\r\n", &schema(columns, Header::Present)).unwrap();
+        assert_eq!(resolved.header.unwrap(), ["id", "name"]);
+        assert_eq!(resolved.input, b"1,a\r\n");
+        assert_eq!(resolved.schema.columns[0].index, Some(1));
+        assert_eq!(resolved.schema.columns[1].name, "id");
+
+        let missing = schema(
+            vec![Column::named("price", ColumnType::F64)],
+            Header::Present,
+        );
+        assert!(resolve(b"id,name\n", &missing).is_err());
+        let headerless = schema(vec![Column::named("id", ColumnType::U32)], Header::Absent);
+        assert!(resolve(b"1,a\n", &headerless).is_err());
+        let unnamed = schema(vec![Column::at(1, ColumnType::Utf8)], Header::Absent);
+        assert_eq!(
+            resolve(b"1,a\n", &unnamed).unwrap().schema.columns[0].name,
+            "col1"
+        );
+    }
+
+    #[test]
+    fn cuts_matching_headers_off_every_input() {
+        let schema = schema(
+            vec![Column::named("name", ColumnType::Utf8)],
+            Header::Present,
+        );
+        let inputs = ["id,name\n1,a\n", "id,\"name\"\r\n2,b\n", "", "id,name"];
+        let (resolved, data) = read_inputs(&inputs, &schema).unwrap();
+        assert_eq!(resolved.columns[0].index, Some(1));
+        assert_eq!(data, b"1,a\n2,b\n");
+    }
+
+    #[test]
+    fn rejects_mismatched_headers() {
+        let schema = schema(
+            vec![Column::named("name", ColumnType::Utf8)],
+            Header::Present,
+        );
+        let inputs = ["id,name\n1,a\n", "id,name\n2,b\n", "name,id\nb,2\n"];
+        let Err(NvParseError::MalformedInput(msg)) = read_inputs(&inputs, &schema) else {
+            panic!("the third input's header was accepted");
+        };
+        assert!(msg.contains("input 3"), "{}", msg);
+    }
+
+    #[test]
+    fn keeps_first_lines_without_a_header() {
+        let schema = schema(vec![Column::at(0, ColumnType::U32)], Header::Detect);
+        let (_, data) = read_inputs(&["1,a\n", "2,b\n"], &schema).unwrap();
+        assert_eq!(data, b"1,a\n2,b\n");
+    }
+
+    #[test]
+    fn rejects_invalid_dialects() {
+        let schema = Schema::new(vec![Column::at(0, ColumnType::U32)])
+            .dialect(Dialect::rfc4180().decimal(b','));
+        let Err(NvParseError::InvalidSchema(_)) = read_inputs(&["1\n"], &schema) else {
+            panic!("the dialect was accepted");
+        };
+    }
+}
@@ -0,0 +1,852 @@
+//! Runs the kernels on the host by calling them once for every invocation of each dispatch.
+//!
+//! This is much slower than the GPU, but needs no adapter and gives a reference to diff the GPU's
+//! output against. Invocations run one at a time and in order, which is what lets
+//! `kernelcodegen::atomic` emulate atomics with plain reads and writes, and `kernelcodegen::scan`
+//! scan a workgroup's values without barriers. Chunks don't share any buffers though, so they're
+//! parsed in parallel on rayon's thread pool, which makes the host a usable fallback on machines
+//! without a GPU.
+use std::io::BufRead;
+
+use kernelcodegen::{bytes, scan};
+use rayon::prelude::*;
+use spirv_std::glam::UVec3;
+
+use crate::driver::{
+    dispatch_size, errors_buffer_size, read_chunk, scan_passes, ChunkSink, LineStitcher,
+    PipelineConfig, PipelineOutput,
+};
+use crate::error::NvParseError;
+use crate::schema::ColumnType;
+
+/// Bytes of input handed to the kernels at a time
+const CHUNK_SIZE: usize = 1 << 24;
+
+/// Calls `invocation(local_invocation_id, global_invocation_id)` for every invocation of
+/// `n_workgroups` workgroups laid out along x.
+fn dispatch<F: FnMut(UVec3, UVec3)>(
+    n_workgroups: u32,
+    workgroup_dim: (u32, u32, u32),
+    mut invocation: F,
+) {
+    for workgroup in 0..n_workgroups {
+        for x in 0..workgroup_dim.0 {
+            let lid = UVec3::new(x, 0, 0);
+            let id = UVec3::new(workgroup * workgroup_dim.0 + x, 0, 0);
+            invocation(lid, id);
+        }
+    }
+}
+
+/// For every invocation of countchar, whether its range of `input` starts inside quotes. See
+/// quoteparity.
+fn quote_state(
+    input: &[u32],
+    data_len: u32,
+    chunk_size: u32,
+    n_dispatches: u32,
+    quote: u32,
+    in_quotes: bool,
+) -> Vec<u32> {
+    let initial_state = in_quotes as u32;
+    let n_threads = n_dispatches * quoteparity::codegen::WORKGROUP_DIM.0;
+    let mut sums = vec![0; scan::sums_len(n_threads) as usize];
+    let mut in_quotes = vec![0; n_threads.div_ceil(32) as usize];
+    let mut shared = [0; scan::BLOCK as usize];
+    let passes = std::iter::once((quoteparity::PASS_COUNT, n_dispatches))
+        .chain(scan_passes(quoteparity::PASS_SCAN, n_threads))
+        .chain(std::iter::once((quoteparity::PASS_RESOLVE, n_dispatches)));
+    for (pass, n_workgroups) in passes {
+        dispatch(
+            n_workgroups,
+            quoteparity::codegen::WORKGROUP_DIM,
+            |lid, id| {
+                quoteparity::main_quoteparity(
+                    lid,
+                    id,
+                    input,
+                    &chunk_size,
+                    &data_len,
+                    &quote,
+                    &pass,
+                    &initial_state,
+                    &mut sums,
+                    &n_threads,
+                    &mut in_quotes,
+                    &mut shared,
+                )
+            },
+        );
+    }
+    in_quotes
+}
+
+/// Runs every pass of dictencode over the (offset, length) pairs written by parsecsv, returning
+/// the code of every row and the pairs of the distinct strings.
+#[allow(clippy::too_many_arguments)]
+fn dictionary_encode(
+    input: &[u32],
+    residual: &[u32],
+    residual_len: u32,
+    quote: u32,
+    lines_per_thread: u32,
+    n_dispatches: u32,
+    strings: &[u32],
+    validity: &[u32],
+) -> (Vec<u32>, Vec<u32>) {
+    let nlines = strings.len() as u32 / 2;
+    let table_size = dictencode::table_size(nlines) as usize;
+    let mut table = vec![0; table_size];
+    let mut first_rows = vec![0; table_size];
+    let n_threads = n_dispatches * dictencode::codegen::WORKGROUP_DIM.0;
+    let mut sums = vec![0; scan::sums_len(n_threads) as usize];
+    let mut entries = vec![0; nlines as usize * 2];
+    let mut codes = vec![0; nlines as usize];
+    let mut shared = [0; scan::BLOCK as usize];
+    let passes = [
+        (dictencode::PASS_INSERT, n_dispatches),
+        (dictencode::PASS_COUNT, n_dispatches),
+    ]
+    .into_iter()
+    .chain(scan_passes(dictencode::PASS_SCAN, n_threads))
+    .chain([
+        (dictencode::PASS_ASSIGN, n_dispatches),
+        (dictencode::PASS_RESOLVE, n_dispatches),
+    ]);
+    for (pass, n_workgroups) in passes {
+        dispatch(
+            n_workgroups,
+            dictencode::codegen::WORKGROUP_DIM,
+            |lid, id| {
+                dictencode::main_dictencode(
+                    lid,
+                    id,
+                    residual,
+                    &residual_len,
+                    input,
+                    &lines_per_thread,
+                    &pass,
+                    &quote,
+                    strings,
+                    validity,
+                    &mut table,
+                    &mut first_rows,
+                    &mut sums,
+                    &n_threads,
+                    &mut entries,
+                    &mut codes,
+                    &mut shared,
+                )
+            },
+        );
+    }
+    let n_entries = sums[scan::total_offset(n_threads) as usize];
+    entries.truncate(n_entries as usize * 2);
+    (codes, entries)
+}
+
+/// Compacts the strings given by (offset, length) `pairs` with gatherstrings, returning their
+/// offsets and values.
+fn gather_strings(
+    input: &[u32],
+    residual: &[u32],
+    residual_len: u32,
+    quote: u32,
+    lines_per_thread: u32,
+    n_dispatches: u32,
+    pairs: &[u32],
+) -> (Vec<u32>, Vec<u8>) {
+    let n_strings = [pairs.len() as u32 / 2];
+    let n_threads = n_dispatches * gatherstrings::codegen::WORKGROUP_DIM.0;
+    let mut sums = vec![0; scan::sums_len(n_threads) as usize];
+    let mut offsets = vec![0; n_strings[0] as usize + 1];
+    let mut values = Vec::new();
+    let mut shared = [0; scan::BLOCK as usize];
+    let passes = std::iter::once((gatherstrings::PASS_COUNT, n_dispatches))
+        .chain(scan_passes(gatherstrings::PASS_SCAN, n_threads))
+        .chain([
+            (gatherstrings::PASS_OFFSETS, n_dispatches),
+            (gatherstrings::PASS_GATHER, n_dispatches),
+        ]);
+    for (pass, n_workgroups) in passes {
+        if pass == gatherstrings::PASS_GATHER {
+            let values_len = sums[scan::total_offset(n_threads) as usize] as usize;
+            values = vec![0; values_len.div_ceil(4)];
+        }
+        dispatch(
+            n_workgroups,
+            gatherstrings::codegen::WORKGROUP_DIM,
+            |lid, id| {
+                gatherstrings::main_gatherstrings(
+                    lid,
+                    id,
+                    residual,
+                    &residual_len,
+                    input,
+                    &lines_per_thread,
+                    pairs,
+                    &mut offsets,
+                    &mut values,
+                    &quote,
+                    &pass,
+                    &n_strings,
+                    &mut sums,
+                    &n_threads,
+                    &mut shared,
+                )
+            },
+        );
+    }
+    let mut values: Vec<u8> = values.iter().flat_map(|w| w.to_le_bytes()).collect();
+    values.truncate(offsets[offsets.len() - 1] as usize);
+    (offsets, values)
+}
+
+/// Runs parsecsv over every line of the chunk for the field `index` as a `ty` laid out by
+/// `format`, returning the parsed words, validity and errors.
+#[allow(clippy::too_many_arguments)]
+fn parse_column(
+    input: &[u32],
+    data_len: u32,
+    residual: &[u32],
+    residual_len: u32,
+    config: &PipelineConfig,
+    lines_per_thread: u32,
+    n_dispatches: u32,
+    line_end_offsets: &mut [u32],
+    index: u32,
+    ty: u32,
+    nullable: bool,
+    format: &[u32],
+) -> (Vec<u32>, Vec<u32>, Vec<u32>) {
+    let nlines = line_end_offsets.len();
+    let quote = u32::from(config.quote.unwrap_or(0));
+    let delimiter = u32::from(config.delimiter);
+    let decimal = u32::from(config.decimal);
+    let strip_cr = config.strip_cr as u32;
+    let nullable = nullable as u32;
+    let mut parsed = vec![0; nlines * parsecsv::type_width(ty)];
+    let mut validity = vec![0; nlines.div_ceil(32)];
+    let mut errors = vec![0; errors_buffer_size() / 4];
+    dispatch(n_dispatches, parsecsv::codegen::WORKGROUP_DIM, |_, id| {
+        parsecsv::main_cc(
+            id,
+            residual,
+            &residual_len,
+            input,
+            &data_len,
+            &delimiter,
+            &lines_per_thread,
+            line_end_offsets,
+            &mut parsed,
+            &index,
+            &ty,
+            &nullable,
+            &mut validity,
+            &mut errors,
+            &quote,
+            &strip_cr,
+            &decimal,
+            &parsecsv::POWERS_OF_FIVE,
+            format,
+        )
+    });
+    (parsed, validity, errors)
+}
+
+/// Parses a single chunk of input. If `in_quotes` is set, the chunk starts inside a quoted field.
+/// The lines of errors are counted from the start of the chunk.
+fn run_chunk(
+    input: &[u8],
+    residual: &[u8],
+    config: &PipelineConfig,
+    in_quotes: bool,
+) -> PipelineOutput {
+    let mut output = PipelineOutput::new(&config.columns);
+    let max_workgroups = wgpu::Limits::default().max_compute_workgroups_per_dimension;
+    let data_len = input.len() as u32;
+    let residual_len = residual.len() as u32;
+    let input = bytes::pack(input);
+    let residual = bytes::pack(residual);
+    let (n_dispatches, chunk_size) = dispatch_size(
+        data_len,
+        countchar::codegen::WORKGROUP_DIM.0,
+        max_workgroups,
+    );
+
+    let char = u32::from(config.char);
+    let quote = u32::from(config.quote.unwrap_or(0));
+    let in_quotes = match config.quote {
+        Some(_) => quote_state(&input, data_len, chunk_size, n_dispatches, quote, in_quotes),
+        None => vec![0],
+    };
+
+    let n_threads = n_dispatches * countchar::codegen::WORKGROUP_DIM.0;
+    let mut sums = vec![0; scan::sums_len(n_threads) as usize];
+    let mut shared = [0; scan::BLOCK as usize];
+    let passes = std::iter::once((countchar::PASS_COUNT, n_dispatches))
+        .chain(scan_passes(countchar::PASS_SCAN, n_threads));
+    for (pass, n_workgroups) in passes {
+        dispatch(
+            n_workgroups,
+            countchar::codegen::WORKGROUP_DIM,
+            |lid, id| {
+                countchar::main_cc(
+                    lid,
+                    id,
+                    &input,
+                    &chunk_size,
+                    &data_len,
+                    &char,
+                    &mut sums,
+                    &quote,
+                    &in_quotes,
+                    &pass,
+                    &n_threads,
+                    &mut shared,
+                )
+            },
+        );
+    }
+    let nlines = sums[scan::total_offset(n_threads) as usize];
+    if nlines == 0 {
+        return output;
+    }
+    output.nlines = nlines.into();
+
+    let mut line_end_offsets = vec![0; nlines as usize];
+    dispatch(n_dispatches, getcharpos::codegen::WORKGROUP_DIM, |_, id| {
+        getcharpos::main_getcharpos(
+            id,
+            &input,
+            &chunk_size,
+            &data_len,
+            &char,
+            &sums,
+            &n_threads,
+            &mut line_end_offsets,
+            &quote,
+            &in_quotes,
+        )
+    });
+
+    let lines_per_thread = nlines.div_ceil(n_dispatches * parsecsv::codegen::WORKGROUP_DIM.0);
+    let columns = config.columns.iter().zip(&config.formats);
+    for ((column, format), parsed_column) in columns.zip(output.columns.iter_mut()) {
+        let index = column.field_index();
+        let ty = column.ty.kernel_type();
+        let (parsed, validity, errors) = parse_column(
+            &input,
+            data_len,
+            &residual,
+            residual_len,
+            config,
+            lines_per_thread,
+            n_dispatches,
+            &mut line_end_offsets,
+            index,
+            ty,
+            column.nullable,
+            format,
+        );
+        let (words, pairs) = match column.ty {
+            ColumnType::Utf8 => (Vec::new(), Some(parsed)),
+            ColumnType::Dictionary => {
+                let (codes, entries) = dictionary_encode(
+                    &input,
+                    &residual,
+                    residual_len,
+                    quote,
+                    lines_per_thread,
+                    n_dispatches,
+                    &parsed,
+                    &validity,
+                );
+                (codes, Some(entries))
+            }
+            _ => (parsed, None),
+        };
+        let (offsets, values) = pairs
+            .map(|pairs| {
+                gather_strings(
+                    &input,
+                    &residual,
+                    residual_len,
+                    quote,
+                    lines_per_thread,
+                    n_dispatches,
+                    &pairs,
+                )
+            })
+            .unzip();
+        parsed_column.append_chunk(
+            &words,
+            offsets.as_deref().zip(values),
+            &validity,
+            &errors,
+            nlines,
+            0,
+        );
+    }
+
+    output
+}
+
+/// A chunk of the input that has been read, but not parsed yet
+struct Chunk {
+    data: Vec<u8>,
+    /// See `driver::Chunk::residual`
+    residual: Vec<u8>,
+    /// Whether the chunk starts inside a quoted field
+    in_quotes: bool,
+    /// Index of the input the chunk was read from
+    input: u32,
+}
+
+/// Parses the chunks of `batch` in parallel, and hands their output to `sink` in order. `nlines`
+/// is the number of lines before the batch, and the number of lines after it is returned.
+fn run_batch<S: ChunkSink>(
+    batch: &mut Vec<Chunk>,
+    config: &PipelineConfig,
+    mut nlines: u64,
+    sink: &mut S,
+) -> Result<u64, NvParseError> {
+    let outputs: Vec<PipelineOutput> = batch
+        .par_iter()
+        .map(|chunk| run_chunk(&chunk.data, &chunk.residual, config, chunk.in_quotes))
+        .collect();
+    for (chunk, mut output) in batch.drain(..).zip(outputs) {
+        if output.nlines == 0 {
+            continue;
+        }
+        // A chunk's lines can only be numbered once the chunks before it have been counted
+        for column in &mut output.columns {
+            for (line, _) in &mut column.errors {
+                *line += nlines;
+            }
+        }
+        nlines += output.nlines;
+        output.inputs.push((chunk.input, output.nlines));
+        sink.push_chunk(output)?;
+    }
+    Ok(nlines)
+}
+
+/// Equivalent to `driver::run_pipeline`, but runs every kernel on the host. Chunks are read a
+/// batch at a time, with as many chunks in a batch as rayon has threads.
+pub(crate) fn run_pipeline<I, R, S>(
+    inputs: I,
+    config: &PipelineConfig,
+    mut sink: S,
+) -> Result<S, NvParseError>
+where
+    I: IntoIterator<Item = Result<R, NvParseError>>,
+    R: BufRead,
+    S: ChunkSink,
+{
+    let batch_size = rayon::current_num_threads();
+    let mut batch = Vec::with_capacity(batch_size);
+    let mut nlines = 0;
+    for (index, input) in inputs.into_iter().enumerate() {
+        let mut input = input?;
+        let mut stitcher = LineStitcher::new(config.char, config.quote);
+        let mut last = input.fill_buf()?.is_empty();
+        while !last {
+            let mut data = vec![0; CHUNK_SIZE];
+            let len = read_chunk(&mut input, &mut data)?;
+            data.truncate(len);
+            last = input.fill_buf()?.is_empty();
+            let terminate = stitcher.needs_terminator(&data, last);
+
+            let in_quotes = stitcher.in_quotes();
+            let residual = stitcher.next_residual(&data, last)?;
+            if terminate {
+                data.push(config.char);
+            }
+            batch.push(Chunk {
+                data,
+                residual,
+                in_quotes,
+                input: index as u32,
+            });
+            if batch.len() == batch_size {
+                nlines = run_batch(&mut batch, config, nlines, &mut sink)?;
+            }
+        }
+    }
+    run_batch(&mut batch, config, nlines, &mut sink)?;
+
+    Ok(sink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::driver::ChunkSink;
+    use crate::schema::{Column, ColumnType, Dialect, Schema, Terminator};
+    use crate::session::ColumnData;
+    use crate::strings::Strings;
+
+    fn config(columns: &[(usize, ColumnType)], dialect: Dialect) -> PipelineConfig {
+        let columns = columns
+            .iter()
+            .map(|&(index, ty)| Column::new(&format!("column {}", index), index, ty))
+            .collect();
+        PipelineConfig::new(&Schema::new(columns).dialect(dialect))
+    }
+
+    /// Parses `input` in chunks of `chunk_len` bytes, stitching lines across them as
+    /// `run_pipeline` does
+    fn parse_chunks(input: &[u8], chunk_len: usize, config: &PipelineConfig) -> PipelineOutput {
+        let mut output = PipelineOutput::new(&config.columns);
+        let mut stitcher = LineStitcher::new(config.char, config.quote);
+        let chunks: Vec<&[u8]> = input.chunks(chunk_len).collect();
+        for (i, slice) in chunks.iter().enumerate() {
+            let last = i == chunks.len() - 1;
+            let terminate = stitcher.needs_terminator(slice, last);
+            let in_quotes = stitcher.in_quotes();
+            let residual = stitcher.next_residual(slice, last).unwrap();
+            let mut chunk = slice.to_vec();
+            if terminate {
+                chunk.push(config.char);
+            }
+            let mut chunk_output = run_chunk(&chunk, &residual, config, in_quotes);
+            for column in &mut chunk_output.columns {
+                for (line, _) in &mut column.errors {
+                    *line += output.nlines;
+                }
+            }
+            output.push_chunk(chunk_output).unwrap();
+        }
+        output
+    }
+
+    fn strings(values: &[&str]) -> Strings {
+        let mut strings = Strings::new();
+        for value in values {
+            strings.push(value);
+        }
+        strings
+    }
+
+    /// The words of the first two columns of every row
+    fn rows(output: &PipelineOutput) -> Vec<(u32, u32)> {
+        let (first, second) = (&output.columns[0].words, &output.columns[1].words);
+        first.iter().copied().zip(second.iter().copied()).collect()
+    }
+
+    fn values(output: &PipelineOutput, config: &PipelineConfig, column: usize) -> ColumnData {
+        let column_output = &output.columns[column];
+        let words = column_output.words.clone();
+        ColumnData::from_words(
+            config.columns[column].ty,
+            words,
+            column_output.strings.clone(),
+        )
+    }
+
+    /// The id in the first column and string in the second column of every row
+    fn string_rows(output: &PipelineOutput) -> Vec<(u32, String)> {
+        let strings = &output.columns[1].strings;
+        (output.columns[0].words.iter().enumerate())
+            .map(|(row, &id)| (id, strings.get(row).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_typed_columns() {
+        let columns = [
+            (0, ColumnType::U32),
+            (1, ColumnType::F64),
+            (2, ColumnType::I64),
+            (3, ColumnType::Bool),
+            (4, ColumnType::Utf8),
+        ];
+        let config = config(&columns, Dialect::default());
+        let input = b"1|2.5|-7|true|a\n2|-1000.25|8000000000|false|bc\n".to_vec();
+        // The first line of a chunk is always parsed from the residual
+        let residual = b"1|2.5|-7|true|a\n".to_vec();
+        let output = run_chunk(&input, &residual, &config, false);
+        assert_eq!(output.nlines, 2);
+        assert_eq!(values(&output, &config, 0), ColumnData::U32(vec![1, 2]));
+        assert_eq!(
+            values(&output, &config, 1),
+            ColumnData::F64(vec![2.5, -1000.25])
+        );
+        assert_eq!(
+            values(&output, &config, 2),
+            ColumnData::I64(vec![-7, 8_000_000_000])
+        );
+        assert_eq!(
+            values(&output, &config, 3),
+            ColumnData::Bool(vec![true, false])
+        );
+        assert_eq!(
+            values(&output, &config, 4),
+            ColumnData::Utf8(strings(&["a", "bc"]))
+        );
+    }
+
+    #[test]
+    fn parses_the_residual_first() {
+        let config = config(&[(1, ColumnType::U32)], Dialect::default());
+        // The residual holds the whole of the first line, which the chunk starts in the middle of
+        let residual = b"a|1\n".to_vec();
+        let input = b"b|1\nb|2\nc|3\n".to_vec();
+        let output = run_chunk(&input, &residual, &config, false);
+        assert_eq!(output.nlines, 3);
+        assert_eq!(output.columns[0].words, [1, 2, 3]);
+    }
+
+    #[test]
+    fn matches_across_chunk_sizes() {
+        let config = config(
+            &[(0, ColumnType::U32), (1, ColumnType::I32)],
+            Dialect::default(),
+        );
+        let input: Vec<u8> = (0..60)
+            .flat_map(|i| format!("{}|{}\n", i, -i).into_bytes())
+            .collect();
+        for chunk_len in [1, 2, 3, 7, 64, 1000] {
+            let output = parse_chunks(&input, chunk_len, &config);
+            assert_eq!(output.nlines, 60, "chunk length {}", chunk_len);
+            let expected: Vec<(u32, u32)> = (0..60).map(|i: i32| (i as u32, -i as u32)).collect();
+            assert_eq!(rows(&output), expected, "chunk length {}", chunk_len);
+        }
+        // An unterminated last line is parsed as if it had a terminator
+        let output = parse_chunks(b"1|2\n3|4", 5, &config);
+        assert_eq!(rows(&output), [(1, 2), (3, 4)]);
+    }
+
+    #[test]
+    fn parses_quoted_fields_across_chunks() {
+        let dialect = Dialect::default().quote(Some(b'"'));
+        let config = config(&[(0, ColumnType::U32), (2, ColumnType::I32)], dialect);
+        let input = b"1|\"a\nb\"|10\n\"2\"|\"c|\"\"d\"\"\"|-20\n3|e|30";
+        for chunk_len in [1, 2, 5, 100] {
+            let output = parse_chunks(input, chunk_len, &config);
+            assert_eq!(output.nlines, 3, "chunk length {}", chunk_len);
+            let expected = [(1, 10), (2, -20i32 as u32), (3, 30)];
+            assert_eq!(rows(&output), expected, "chunk length {}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn tracks_quotes_across_workgroups() {
+        // Enough lines for the scans of quoteparity and countchar to take several levels
+        let dialect = Dialect::default().quote(Some(b'"'));
+        let config = config(&[(0, ColumnType::U32), (2, ColumnType::U32)], dialect);
+        let input: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("{}|\"a\n|\"\"b\"\"\"|{}\n", i, i * 2).into_bytes())
+            .collect();
+        let output = parse_chunks(&input, input.len(), &config);
+        let expected: Vec<(u32, u32)> = (0..2000).map(|i| (i, i * 2)).collect();
+        assert_eq!(rows(&output), expected);
+    }
+
+    #[test]
+    fn gathers_strings_across_chunks() {
+        let config = config(
+            &[(0, ColumnType::U32), (1, ColumnType::Utf8)],
+            Dialect::default(),
+        );
+        let input: Vec<u8> = (0..60)
+            .flat_map(|i| format!("{}|name {}\n", i, i).into_bytes())
+            .collect();
+        let expected: Vec<(u32, String)> = (0..60).map(|i| (i, format!("name {}", i))).collect();
+        for chunk_len in [1, 2, 3, 7, 64, 1000] {
+            let output = parse_chunks(&input, chunk_len, &config);
+            assert_eq!(string_rows(&output), expected, "chunk length {}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn gathers_strings_across_workgroups() {
+        // Enough rows for the scan of the strings' lengths to take several levels
+        let config = config(
+            &[(0, ColumnType::U32), (1, ColumnType::Utf8)],
+            Dialect::default(),
+        );
+        let expected: Vec<(u32, String)> =
+            (0..5000).map(|i| (i, "x".repeat(i as usize % 7))).collect();
+        let input: Vec<u8> = expected
+            .iter()
+            .flat_map(|(i, s)| format!("{}|{}\n", i, s).into_bytes())
+            .collect();
+        let output = parse_chunks(&input, input.len(), &config);
+        assert_eq!(string_rows(&output), expected);
+    }
+
+    #[test]
+    fn unquotes_strings_across_chunks() {
+        // Quotes around a string are dropped, and doubled quotes inside it are unescaped
+        let config = config(
+            &[(0, ColumnType::U32), (1, ColumnType::Utf8)],
+            Dialect::rfc4180(),
+        );
+        let input = b"1,\"a\r\nb\"\r\n2,\"c,\"\"d\"\"\"\r\n3,e";
+        let expected = [(1, "a\r\nb"), (2, "c,\"d\""), (3, "e")];
+        let expected: Vec<(u32, String)> = expected.map(|(id, s)| (id, s.to_string())).to_vec();
+        for chunk_len in [1, 2, 5, 100] {
+            let output = parse_chunks(input, chunk_len, &config);
+            assert_eq!(string_rows(&output), expected, "chunk length {}", chunk_len);
+        }
+    }
+
+    /// The string of every row of a dictionary column, or `None` for null rows, and the number of
+    /// strings in its dictionary
+    fn decode_dictionary(
+        output: &PipelineOutput,
+        config: &PipelineConfig,
+    ) -> (Vec<Option<String>>, usize) {
+        let validity = &output.columns[0].validity;
+        let ColumnData::Dictionary { codes, values } = values(output, config, 0) else {
+            panic!("not a dictionary column");
+        };
+        let rows = codes
+            .iter()
+            .zip(validity.iter())
+            .map(|(&code, valid)| valid.then(|| values.get(code as usize).to_string()))
+            .collect();
+        (rows, values.len())
+    }
+
+    #[test]
+    fn dictionary_encodes_strings() {
+        let column = Column::at(1, ColumnType::Dictionary).nullable(true);
+        let config = PipelineConfig::new(&Schema::new(vec![column]).dialect(Dialect::rfc4180()));
+        let names = [
+            "red", "green", "", "red", "\"blue\"", "green", "red", "blue",
+        ];
+        let input: Vec<u8> = names
+            .iter()
+            .enumerate()
+            .flat_map(|(i, name)| format!("{},{}\n", i, name).into_bytes())
+            .collect();
+        let expected: Vec<Option<String>> = names
+            .iter()
+            .map(|name| (!name.is_empty()).then(|| name.trim_matches('"').to_string()))
+            .collect();
+        // Every chunk has its own dictionary, which are merged as the chunks are appended
+        for chunk_len in [3, 10, 1000] {
+            let output = parse_chunks(&input, chunk_len, &config);
+            let (rows, n_values) = decode_dictionary(&output, &config);
+            assert_eq!(rows, expected, "chunk length {}", chunk_len);
+            assert_eq!(n_values, 3, "chunk length {}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn dictionary_encodes_many_distinct_strings() {
+        let config = config(&[(0, ColumnType::Dictionary)], Dialect::default());
+        let input: Vec<u8> = (0..3000)
+            .flat_map(|i| format!("value {}\n", i % 1000).into_bytes())
+            .collect();
+        let output = parse_chunks(&input, input.len(), &config);
+        let (rows, n_values) = decode_dictionary(&output, &config);
+        assert_eq!(n_values, 1000);
+        let expected: Vec<Option<String>> = (0..3000)
+            .map(|i| Some(format!("value {}", i % 1000)))
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn strips_carriage_returns() {
+        let columns = [(0, ColumnType::U32), (1, ColumnType::I32)];
+        let input = b"1,-1\r\n2,-2\n3,-3\r\n";
+        let crlf = config(&columns, Dialect::rfc4180());
+        let expected = [(1, -1i32 as u32), (2, -2i32 as u32), (3, -3i32 as u32)];
+        assert_eq!(rows(&parse_chunks(input, 4, &crlf)), expected);
+        // With `\n` as the terminator, the `\r` is part of the last field
+        let lf = config(&columns, Dialect::rfc4180().terminator(Terminator::Lf));
+        let output = parse_chunks(input, 4, &lf);
+        assert_eq!(
+            output.columns[1]
+                .validity
+                .iter()
+                .filter(|valid| !valid)
+                .count(),
+            2
+        );
+        // Any other byte may end the lines
+        let semicolon = config(
+            &columns,
+            Dialect::default().terminator(Terminator::Byte(b';')),
+        );
+        assert_eq!(
+            rows(&parse_chunks(b"1|4;2|5", 3, &semicolon)),
+            [(1, 4), (2, 5)]
+        );
+    }
+
+    #[test]
+    fn defaults_to_lf_terminators() {
+        let columns = [(0, ColumnType::U32), (1, ColumnType::I32)];
+        let config = config(&columns, Dialect::default());
+        let expected = [(1, -1i32 as u32), (2, -2i32 as u32)];
+        assert_eq!(rows(&parse_chunks(b"1|-1\n2|-2\n", 4, &config)), expected);
+        // A `\r` is only dropped if CRLF is asked for
+        let output = parse_chunks(b"1|-1\r\n2|-2\r\n", 4, &config);
+        assert_eq!(output.columns[1].n_errors, 2);
+    }
+
+    #[test]
+    fn numbers_errors_across_chunks() {
+        let config = config(&[(0, ColumnType::U32)], Dialect::default());
+        let output = parse_chunks(b"1\nx\n3\n\n5\n-6", 3, &config);
+        let column = &output.columns[0];
+        let validity: Vec<bool> = column.validity.iter().collect();
+        assert_eq!(validity, [true, false, true, false, true, false]);
+        assert_eq!(column.n_errors, 3);
+        let errors = [
+            (1, parsecsv::ERROR_INVALID),
+            (3, parsecsv::ERROR_EMPTY),
+            (5, parsecsv::ERROR_INVALID),
+        ];
+        assert_eq!(column.errors, errors);
+    }
+
+    #[test]
+    fn runs_the_pipeline() {
+        let config = config(&[(0, ColumnType::U32)], Dialect::default());
+        let run = |input: &[u8]| {
+            let inputs = [Ok(input)];
+            run_pipeline(inputs, &config, PipelineOutput::new(&config.columns)).unwrap()
+        };
+        let output = run(b"4\n5\n6");
+        assert_eq!(output.nlines, 3);
+        assert_eq!(output.columns[0].words, [4, 5, 6]);
+        assert_eq!(output.inputs, [(0, 3)]);
+        assert_eq!(run(b"").nlines, 0);
+    }
+
+    #[test]
+    fn runs_the_pipeline_over_several_inputs() {
+        let config = config(&[(0, ColumnType::U32)], Dialect::default());
+        // Each input ends its own last line, and empty inputs don't add any lines
+        let inputs: [Result<&[u8], _>; 4] = [Ok(b"1\n2"), Ok(b"3\n"), Ok(b""), Ok(b"4")];
+        let output = run_pipeline(inputs, &config, PipelineOutput::new(&config.columns)).unwrap();
+        assert_eq!(output.nlines, 4);
+        assert_eq!(output.columns[0].words, [1, 2, 3, 4]);
+        assert_eq!(output.inputs, [(0, 2), (1, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn rejects_an_unterminated_quote() {
+        let config = config(&[(0, ColumnType::U32)], Dialect::rfc4180());
+        let inputs: [Result<&[u8], _>; 1] = [Ok(b"1\n\"2\n3\n")];
+        let output = run_pipeline(inputs, &config, PipelineOutput::new(&config.columns));
+        assert!(matches!(output, Err(NvParseError::MalformedInput(_))));
+    }
+
+    #[test]
+    fn stops_at_a_failed_input() {
+        let config = config(&[(0, ColumnType::U32)], Dialect::default());
+        let failed = NvParseError::MalformedInput("header mismatch".to_string());
+        let inputs: [Result<&[u8], _>; 3] = [Ok(b"1\n"), Err(failed), Ok(b"2\n")];
+        let output = run_pipeline(inputs, &config, PipelineOutput::new(&config.columns));
+        assert!(matches!(output, Err(NvParseError::MalformedInput(_))));
+    }
+}
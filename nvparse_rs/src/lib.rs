@@ -0,0 +1,26 @@
+//! GPU accelerated parsing of delimited text files.
+//!
+//! A `Session` owns the GPU device used for parsing, and turns raw bytes into typed columns
+//! described by a `Schema`, either all at once as a `Table` or streamed out as Arrow record
+//! batches.
+pub mod bitmap;
+mod context;
+pub mod decompress;
+mod driver;
+pub mod error;
+mod header;
+mod host;
+mod record_batch;
+pub mod schema;
+pub mod session;
+pub mod strings;
+
+pub use bitmap::Bitmap;
+pub use context::{AdapterChoice, AdapterSelector, GpuContext};
+pub use decompress::{Compression, Decompressor};
+pub use driver::MAX_ERRORS_PER_CHUNK;
+pub use error::{NvParseError, ParseError, ParseErrorKind};
+pub use record_batch::BatchSummary;
+pub use schema::{Column, ColumnType, DateFormat, Dialect, Header, Schema, Terminator};
+pub use session::{ColumnData, Provenance, Session, Table};
+pub use strings::Strings;
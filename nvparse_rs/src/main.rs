@@ -1,57 +1,541 @@
-#![feature(async_closure)]
-use clap::Parser;
-use memmap::MmapOptions;
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use clap::{Parser, ValueEnum};
+use nvparse_rs::decompress;
+use nvparse_rs::schema::{parse_byte, FILE_COLUMN, ROW_COLUMN};
+use nvparse_rs::{
+    AdapterChoice, AdapterSelector, Column, ColumnData, ColumnType, DateFormat, Dialect,
+    GpuContext, Header, NvParseError, ParseError, Schema, Session, Table, Terminator,
+};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use std::borrow::Cow;
 use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::thread;
 
-pub mod driver;
+/// Parses a column spec of the form `INDEX[:TYPE]` or `NAME[:TYPE]`, e.g. `3:date`. Columns are
+/// u32 by default, and can only be selected by name if the input has a header.
+fn parse_column(spec: &str) -> Result<Column, String> {
+    let (field, ty) = match spec.split_once(':') {
+        Some((field, ty)) => (field, ty.parse()?),
+        None => (spec, ColumnType::U32),
+    };
+    if field.is_empty() {
+        return Err(format!("Missing column index or name in {}", spec));
+    }
+    Ok(match field.parse() {
+        Ok(index) => Column::at(index, ty),
+        Err(_) => Column::named(field, ty),
+    })
+}
+
+/// The columns given to `--columns`
+#[derive(Clone)]
+struct Columns(Vec<Column>);
+
+/// Parses a comma separated list of column specs, see `parse_column`. Commas inside of
+/// parentheses belong to the type, as in `2:decimal(15,2)`.
+fn parse_columns(specs: &str) -> Result<Columns, String> {
+    let mut columns = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in specs.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                columns.push(parse_column(&specs[start..i])?);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    columns.push(parse_column(&specs[start..])?);
+    Ok(Columns(columns))
+}
+
+/// Parses a comma separated list of wgpu backends, e.g. `vulkan,gl`
+fn parse_backends(s: &str) -> Result<wgpu::Backends, String> {
+    let backends = wgpu::util::parse_backends_from_comma_list(s);
+    if backends.is_empty() {
+        return Err(format!(
+            "No backends in {}, expected a list of vulkan, metal, dx12 or gl",
+            s
+        ));
+    }
+    Ok(backends)
+}
+
+/// Formats a decimal stored as `value` times 10^scale
+fn format_decimal(value: i64, scale: u8) -> String {
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", value.unsigned_abs(), width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+    let sign = if value < 0 { "-" } else { "" };
+    if fraction.is_empty() {
+        format!("{}{}", sign, integer)
+    } else {
+        format!("{}{}.{}", sign, integer, fraction)
+    }
+}
+
+/// Formats days since 1970-01-01 as `YYYY-MM-DD`
+fn format_date(days: i32) -> String {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats microseconds since 1970-01-01 00:00:00 as `YYYY-MM-DD HH:MM:SS`, followed by the
+/// fraction of the second if there is one
+fn format_timestamp(micros: i64) -> String {
+    let days = micros.div_euclid(86_400_000_000);
+    let micros = micros.rem_euclid(86_400_000_000);
+    let seconds = micros / 1_000_000;
+    let time = format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    );
+    let fraction = match micros % 1_000_000 {
+        0 => String::new(),
+        fraction => format!(".{:06}", fraction),
+    };
+    format!("{} {}{}", format_date(days as i32), time, fraction)
+}
+
+fn format_value(table: &Table, column: usize, row: usize) -> String {
+    if !table.is_valid(column, row) {
+        return String::new();
+    }
+    match &table.columns[column] {
+        ColumnData::I32(values) => values[row].to_string(),
+        ColumnData::I64(values) => values[row].to_string(),
+        ColumnData::U32(values) => values[row].to_string(),
+        ColumnData::F32(values) => values[row].to_string(),
+        ColumnData::F64(values) => values[row].to_string(),
+        ColumnData::Bool(values) => values[row].to_string(),
+        ColumnData::Date(values) => format_date(values[row]),
+        ColumnData::Timestamp(values) => format_timestamp(values[row]),
+        ColumnData::Utf8(values) => values.get(row).to_owned(),
+        ColumnData::Dictionary { codes, values } => values.get(codes[row] as usize).to_owned(),
+        ColumnData::Decimal { values, scale, .. } => format_decimal(values[row], *scale),
+    }
+}
 
-fn cpu_count_char(data: &[u8], char: u8) -> u32 {
-    let mut acc = 0;
-    // let mut pbar = tqdm::pbar(Some(data.len()));
-    // let mut i = 0;
-    // let mut last_update = 0;
-    for c in data {
-        acc += if *c == char { 1 } else { 0 };
-        // i += 1;
-        // if i % 1024 == 0 {
-        //     let _ = pbar.update(1024);
-        //     last_update = i;
-        // }
+/// Encloses `field` in quotes if it contains a comma, quote or line break, doubling the quotes
+/// inside of it
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
     }
-    // let _ = pbar.update(data.len() - last_update);
-    acc
 }
 
-fn run_count_char(data: &[u8], char: u8) -> u32 {
-    futures::executor::block_on(driver::run_charcount_shader(data, char)).unwrap()
+/// Formats `s` as a JSON string
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
 }
 
-fn count_char(data: &[u8], char: u8) -> Result<u32, Box<dyn std::error::Error>> {
-    // if data.len() < (2 * nthreads) {
-    //     // Insufficient parallelism, reduce on CPU
-    //     Ok(cpu_count_char(data, char))
-    // } else {
-    Ok(run_count_char(data, char))
-    // }
+/// Formats a field as a JSON value. Numbers and bools are written as they are, except for
+/// infinite and NaN floats which JSON has no numbers for, and nulls are `null`.
+fn json_value(table: &Table, column: usize, row: usize) -> String {
+    if !table.is_valid(column, row) {
+        return "null".to_string();
+    }
+    let value = format_value(table, column, row);
+    let is_number = match &table.columns[column] {
+        ColumnData::F32(values) => values[row].is_finite(),
+        ColumnData::F64(values) => values[row].is_finite(),
+        ColumnData::I32(_)
+        | ColumnData::I64(_)
+        | ColumnData::U32(_)
+        | ColumnData::Bool(_)
+        | ColumnData::Decimal { .. } => true,
+        _ => false,
+    };
+    if is_number {
+        value
+    } else {
+        json_string(&value)
+    }
+}
+
+/// The fields of row `row` of `table` formatted for `output`, followed by the provenance of the
+/// row if the table has any
+fn row_fields(table: &Table, row: usize, output: Output) -> Vec<String> {
+    let text = |field: String| match output {
+        Output::Csv => csv_field(&field).into_owned(),
+        Output::Json => json_string(&field),
+        _ => field,
+    };
+    let mut fields: Vec<String> = (0..table.columns.len())
+        .map(|column| match output {
+            Output::Json => json_value(table, column, row),
+            _ => text(format_value(table, column, row)),
+        })
+        .collect();
+    if let Some(provenance) = &table.provenance {
+        fields.push(text(
+            provenance.files[provenance.file[row] as usize].clone(),
+        ));
+        fields.push(provenance.row[row].to_string());
+    }
+    fields
+}
+
+/// Writes every row of `table` to `out` as text, CSV or JSON
+fn write_table<W: Write>(table: &Table, output: Output, out: &mut W) -> std::io::Result<()> {
+    let mut names: Vec<&str> = table
+        .schema
+        .columns
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect();
+    if table.provenance.is_some() {
+        names.extend([FILE_COLUMN, ROW_COLUMN]);
+    }
+    match output {
+        // Columns are only named in text output if the input names them
+        Output::Text if table.header.is_some() => writeln!(out, "{}", names.join("|"))?,
+        Output::Csv => {
+            let names: Vec<Cow<'_, str>> = names.iter().map(|name| csv_field(name)).collect();
+            writeln!(out, "{}", names.join(","))?
+        }
+        _ => {}
+    }
+    let keys: Vec<String> = names.iter().map(|name| json_string(name)).collect();
+    let nrows = table.columns.first().map_or(0, |c| c.len());
+    for row in 0..nrows {
+        let fields = row_fields(table, row, output);
+        match output {
+            Output::Csv => writeln!(out, "{}", fields.join(","))?,
+            Output::Json => {
+                let members: Vec<String> = keys
+                    .iter()
+                    .zip(&fields)
+                    .map(|(key, value)| format!("{}:{}", key, value))
+                    .collect();
+                writeln!(out, "{{{}}}", members.join(","))?
+            }
+            _ => writeln!(out, "{}", fields.join("|"))?,
+        }
+    }
+    out.flush()
+}
+
+/// Where the kernels run
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// The default GPU adapter, failing if there isn't one that can run the kernels
+    Gpu,
+    /// Every core of the CPU. This is slower, but gives the same output as the GPU.
+    Cpu,
+    /// The GPU if it can run the kernels, or the CPU otherwise
+    Auto,
+}
+
+/// What to do with the parsed columns
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// Print every row to stdout, with fields separated by `|`
+    Text,
+    /// Print a line naming the columns and then every row to stdout, as comma separated values
+    /// quoted as described by RFC 4180
+    Csv,
+    /// Print every row to stdout as a JSON object keyed by column name, one object per line
+    Json,
+    /// Write a parquet file to `--out`, with one row group per chunk of input
+    Parquet,
 }
 
 #[derive(Parser)]
 struct Args {
-    filename: String,
+    /// Files to parse one after the other, or `-` to read from stdin. Globs such as
+    /// `lineitem.tbl.*` are expanded. Files compressed with gzip, zstd or lz4 are decompressed as
+    /// they're read.
+    #[arg(required_unless_present = "list_adapters")]
+    filenames: Vec<String>,
+    /// Fields to parse out of each line, as INDEX[:TYPE] or NAME[:TYPE] where TYPE is one of i32,
+    /// i64, u32, f32, f64, bool, date, timestamp, string, dict or decimal(PRECISION,SCALE)
+    #[arg(short, long, default_value = "0", value_parser = parse_columns)]
+    columns: Columns,
+    #[arg(long, value_enum, default_value_t = Backend::Auto)]
+    backend: Backend,
+    /// GPU adapter to parse on, by its index in `--list-adapters` or part of its name. wgpu picks
+    /// one if it's not given.
+    #[arg(long)]
+    adapter: Option<AdapterChoice>,
+    /// Comma separated wgpu backends that adapters may be picked from, e.g. `vulkan,gl`
+    #[arg(long, default_value = "vulkan,metal,dx12,gl", value_parser = parse_backends)]
+    adapter_backends: wgpu::Backends,
+    /// Print the adapters of `--adapter-backends`, and whether they can run the kernels
+    #[arg(long)]
+    list_adapters: bool,
+    /// Character between fields, e.g. `,` or `\t`
+    #[arg(short, long, default_value = "|", value_parser = parse_byte)]
+    delimiter: u8,
+    /// What ends each line: `lf`, `crlf` (which also accepts lines ending in just `\n`), or a
+    /// single character
+    #[arg(long, default_value = "lf")]
+    terminator: Terminator,
+    /// Allow fields to be enclosed in double quotes, as described by RFC 4180
+    #[arg(long)]
+    quoted: bool,
+    /// Whether the first line names the columns: `--header`, `--header=no` or `--header=auto` to
+    /// guess from the first two lines
+    #[arg(
+        long,
+        default_value = "no",
+        default_missing_value = "yes",
+        num_args = 0..=1,
+        require_equals = true
+    )]
+    header: Header,
+    /// Character between the integer and fractional digits of floats, e.g. `,` for numbers
+    /// written as 3,14
+    #[arg(long, default_value = ".", value_parser = parse_byte)]
+    decimal: u8,
+    /// Layout of date fields, using %Y, %m and %d for the year, month and day, e.g. `%d/%m/%Y`
+    #[arg(long, default_value = "%Y-%m-%d")]
+    date_format: DateFormat,
+    /// Layout of timestamp fields, which may also use %H, %M, %S for the time and end in %.f for
+    /// an optional fraction of a second
+    #[arg(long, default_value = "%Y-%m-%d %H:%M:%S%.f")]
+    timestamp_format: DateFormat,
+    /// Add a __file column naming the file each row was read from, and a __row column numbering
+    /// the rows of each file from 0
+    #[arg(long)]
+    provenance: bool,
+    #[arg(long, value_enum, default_value_t = Output::Text)]
+    output: Output,
+    /// File to write to for `--output parquet`
+    #[arg(long, required_if_eq("output", "parquet"))]
+    out: Option<PathBuf>,
+}
+
+/// Where the input is read from
+enum Input {
+    Stdin,
+    Files(Vec<PathBuf>),
+}
+
+/// Reads stdin, decompressing it if it's compressed
+fn stdin() -> std::io::Result<impl Read> {
+    decompress::decompressed(BufReader::new(std::io::stdin()), None)
+}
+
+/// Expands the globs among `filenames`, keeping the files in the order they're given. A glob that
+/// matches nothing is an error, like the name of a file that doesn't exist.
+fn expand_globs(filenames: &[String]) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut paths = Vec::new();
+    for filename in filenames {
+        if filename == "-" {
+            return Err("stdin can't be read along with other files".into());
+        }
+        // Files that exist are taken as they are, even if their name looks like a glob
+        if Path::new(filename).exists() || !filename.contains(['*', '?', '[']) {
+            paths.push(PathBuf::from(filename));
+            continue;
+        }
+        let matches = glob::glob(filename)?.collect::<Result<Vec<_>, _>>()?;
+        if matches.is_empty() {
+            return Err(format!("No files match {}", filename).into());
+        }
+        paths.extend(matches);
+    }
+    Ok(paths)
+}
+
+/// Prints every field that failed to parse, returning the first one as an error
+fn report_errors(errors: &[ParseError], n_errors: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(error) = errors.first() {
+        for error in errors {
+            eprintln!("{}", error);
+        }
+        eprintln!("{} fields failed to parse", n_errors);
+        return Err(error.clone().into());
+    }
+    Ok(())
+}
+
+fn create_parquet_writer(
+    path: &Path,
+    schema: SchemaRef,
+) -> parquet::errors::Result<ArrowWriter<File>> {
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        // Row groups are cut by flushing after each batch instead
+        .set_max_row_group_size(usize::MAX)
+        .build();
+    ArrowWriter::try_new(File::create(path)?, schema, Some(props))
+}
+
+/// Parses `input` and writes it to a parquet file at `path`. Record batches are written by their
+/// own thread as they come out of the session, each one as its own row group.
+fn write_parquet(
+    session: &Session,
+    input: &Input,
+    schema: &Schema,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The names of the columns may come from the header, so the file is only created once the
+    // first batch is out
+    let (sender, receiver) = mpsc::channel::<RecordBatch>();
+    let write_thread = {
+        let path = path.to_owned();
+        thread::spawn(
+            move || -> parquet::errors::Result<Option<ArrowWriter<File>>> {
+                let mut writer = None;
+                for batch in receiver {
+                    let writer = match &mut writer {
+                        Some(writer) => writer,
+                        None => writer.insert(create_parquet_writer(&path, batch.schema())?),
+                    };
+                    writer.write(&batch)?;
+                    writer.flush()?;
+                }
+                Ok(writer)
+            },
+        )
+    };
+
+    let on_batch = move |batch| {
+        // If the writer failed it has stopped receiving, and the error is reported by join
+        let _ = sender.send(batch);
+    };
+    let parsed: Result<_, Box<dyn std::error::Error>> = match input {
+        Input::Stdin => stdin().map_err(Into::into).and_then(|reader| {
+            futures::executor::block_on(session.parse_batches_reader(reader, schema, on_batch))
+                .map_err(Into::into)
+        }),
+        Input::Files(paths) => {
+            futures::executor::block_on(session.parse_batches_files(paths, schema, on_batch))
+                .map_err(Into::into)
+        }
+    };
+    // Parsing hangs up on the writer whether or not it succeeded, so the writer is done once the
+    // parse is
+    let written = write_thread.join().map_err(NvParseError::from_panic)?;
+    let summary = match parsed {
+        Ok(summary) => summary,
+        Err(e) => {
+            // Don't leave a file behind that looks like a complete output
+            if let Ok(Some(writer)) = written {
+                drop(writer);
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(e);
+        }
+    };
+    let writer = match written? {
+        Some(writer) => writer,
+        None => create_parquet_writer(path, Arc::new(summary.schema.to_arrow()))?,
+    };
+    writer.close()?;
+    eprintln!("Wrote {} rows to {}", summary.nlines, path.display());
+
+    report_errors(&summary.errors, summary.n_errors)
+}
+
+/// Prints every adapter of `backends` along with its index, for `--adapter`
+fn list_adapters(backends: wgpu::Backends) {
+    for (index, adapter) in GpuContext::adapters(backends).iter().enumerate() {
+        let info = adapter.get_info();
+        let missing = GpuContext::missing_features(adapter);
+        let usable = if missing.is_empty() {
+            String::new()
+        } else {
+            format!(", missing {:?}", missing)
+        };
+        println!(
+            "{}: {} ({:?}, {:?}){}",
+            index, info.name, info.backend, info.device_type, usable
+        );
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    env_logger::init();
     let args = Args::parse();
+    if args.list_adapters {
+        list_adapters(args.adapter_backends);
+        return Ok(());
+    }
 
-    let file = File::open(&args.filename)?;
-    let mmap = unsafe { MmapOptions::new().map(&file)? };
+    let input = if args.filenames == ["-"] {
+        Input::Stdin
+    } else {
+        Input::Files(expand_globs(&args.filenames)?)
+    };
 
-    let nlines = count_char(&mmap, b'\n')?;
+    let selector = AdapterSelector::default()
+        .backends(args.adapter_backends)
+        .adapter(args.adapter);
+    let session = match args.backend {
+        Backend::Gpu => {
+            let context = futures::executor::block_on(GpuContext::with_selector(&selector))?;
+            Session::with_context(Arc::new(context))
+        }
+        Backend::Cpu => Session::new_host(),
+        Backend::Auto => futures::executor::block_on(Session::new_auto(&selector)),
+    };
+    if let Some(e) = session.fallback_reason() {
+        eprintln!("Parsing on the CPU: {}", e);
+    }
+    let dialect = Dialect::default()
+        .delimiter(args.delimiter)
+        .terminator(args.terminator)
+        .quote(args.quoted.then_some(b'"'))
+        .header(args.header)
+        .decimal(args.decimal)
+        .date_format(args.date_format)
+        .timestamp_format(args.timestamp_format);
+    let schema = Schema::new(args.columns.0)
+        .dialect(dialect)
+        .provenance(args.provenance);
+    if args.output == Output::Parquet {
+        return write_parquet(&session, &input, &schema, args.out.as_deref().unwrap());
+    }
+    let table = match &input {
+        Input::Stdin => futures::executor::block_on(session.parse_reader(stdin()?, &schema))?,
+        Input::Files(paths) => futures::executor::block_on(session.parse_files(paths, &schema))?,
+    };
 
-    let timer = std::time::Instant::now();
-    let cpures = cpu_count_char(&mmap, b'\n');
-    eprintln!("CPU time: {:?} (res={})", timer.elapsed(), cpures);
+    write_table(
+        &table,
+        args.output,
+        &mut BufWriter::new(std::io::stdout().lock()),
+    )?;
+    eprintln!("Parsed {} rows", table.nlines);
 
-    println!("{}", nlines);
-    Ok(())
+    report_errors(&table.errors, table.n_errors)
 }
@@ -0,0 +1,154 @@
+//! Converts the output of each chunk into an Arrow `RecordBatch` as soon as it has been read back,
+//! so that it can be handed to Arrow based tools without collecting the whole input first.
+use std::sync::Arc;
+
+use arrow::array::{
+    ArrayRef, BooleanArray, Date32Array, Decimal128Array, DictionaryArray, Float32Array,
+    Float64Array, Int32Array, Int64Array, StringArray, TimestampMicrosecondArray, UInt32Array,
+    UInt64Array,
+};
+use arrow::buffer::{BooleanBuffer, Buffer, NullBuffer, OffsetBuffer, ScalarBuffer};
+use arrow::datatypes::{SchemaRef, UInt32Type};
+use arrow::record_batch::{RecordBatch, RecordBatchOptions};
+
+use crate::bitmap::Bitmap;
+use crate::driver::{ChunkSink, PipelineOutput};
+use crate::error::{NvParseError, ParseError};
+use crate::schema::Schema;
+use crate::session::{self, ColumnData};
+use crate::strings::Strings;
+
+/// What is left over once every record batch of an input has been handed out.
+#[derive(Debug, Clone)]
+pub struct BatchSummary {
+    /// The schema that was parsed, with the names and indices of its columns filled in from the
+    /// header
+    pub schema: Schema,
+    /// The fields of the header line, if the input has one
+    pub header: Option<Vec<String>>,
+    /// Fields that failed to parse, ordered by line. See `Table::errors`.
+    pub errors: Vec<ParseError>,
+    /// Total number of fields that failed to parse, including those missing from `errors`
+    pub n_errors: u64,
+    /// Number of lines in the input, not counting the header
+    pub nlines: u64,
+}
+
+/// Converts a packed validity bitmap into Arrow's representation, which uses the same bit order.
+/// Returns `None` if every row is valid.
+fn null_buffer(validity: &Bitmap) -> Option<NullBuffer> {
+    let bits = BooleanBuffer::new(Buffer::from_slice_ref(validity.words()), 0, validity.len());
+    Some(NullBuffer::new(bits)).filter(|nulls| nulls.null_count() > 0)
+}
+
+fn string_array(strings: &Strings, nulls: Option<NullBuffer>) -> StringArray {
+    // A batch holds a single chunk, which is always small enough for 32 bit offsets
+    let offsets: ScalarBuffer<i32> = strings.offsets().iter().map(|&o| o as i32).collect();
+    StringArray::new(
+        OffsetBuffer::new(offsets),
+        Buffer::from_slice_ref(strings.values()),
+        nulls,
+    )
+}
+
+fn column_array(data: ColumnData, validity: &Bitmap) -> Result<ArrayRef, NvParseError> {
+    let nulls = null_buffer(validity);
+    Ok(match data {
+        ColumnData::I32(values) => Arc::new(Int32Array::new(values.into(), nulls)),
+        ColumnData::I64(values) => Arc::new(Int64Array::new(values.into(), nulls)),
+        ColumnData::U32(values) => Arc::new(UInt32Array::new(values.into(), nulls)),
+        ColumnData::F32(values) => Arc::new(Float32Array::new(values.into(), nulls)),
+        ColumnData::F64(values) => Arc::new(Float64Array::new(values.into(), nulls)),
+        ColumnData::Bool(values) => {
+            Arc::new(BooleanArray::new(values.into_iter().collect(), nulls))
+        }
+        ColumnData::Date(values) => Arc::new(Date32Array::new(values.into(), nulls)),
+        ColumnData::Timestamp(values) => {
+            Arc::new(TimestampMicrosecondArray::new(values.into(), nulls))
+        }
+        ColumnData::Utf8(strings) => Arc::new(string_array(&strings, nulls)),
+        ColumnData::Dictionary { codes, values } => {
+            let keys = UInt32Array::new(codes.into(), nulls);
+            let values = Arc::new(string_array(&values, None));
+            Arc::new(DictionaryArray::<UInt32Type>::try_new(keys, values)?)
+        }
+        ColumnData::Decimal {
+            values,
+            precision,
+            scale,
+        } => {
+            let values = values.into_iter().map(i128::from).collect();
+            Arc::new(
+                Decimal128Array::new(values, nulls)
+                    .with_precision_and_scale(precision, scale as i8)?,
+            )
+        }
+    })
+}
+
+/// Calls `on_batch` with a `RecordBatch` for every chunk of the input.
+pub(crate) struct RecordBatchSink<F> {
+    schema: Schema,
+    arrow_schema: SchemaRef,
+    on_batch: F,
+    summary: BatchSummary,
+    /// Names of the inputs, for the `__file` column
+    files: Arc<StringArray>,
+    /// Rows seen so far of each input, for the `__row` column
+    rows_read: Vec<u64>,
+}
+
+impl<F: FnMut(RecordBatch) + Send + 'static> RecordBatchSink<F> {
+    pub fn new(schema: &Schema, files: Vec<String>, on_batch: F) -> Self {
+        RecordBatchSink {
+            rows_read: vec![0; files.len()],
+            files: Arc::new(StringArray::from(files)),
+            schema: schema.clone(),
+            arrow_schema: Arc::new(schema.to_arrow()),
+            on_batch,
+            summary: BatchSummary {
+                schema: schema.clone(),
+                header: None,
+                errors: Vec::new(),
+                n_errors: 0,
+                nlines: 0,
+            },
+        }
+    }
+
+    pub fn finish(self, header: Option<Vec<String>>) -> BatchSummary {
+        let mut summary = self.summary;
+        summary.header = header;
+        summary.errors.sort_by_key(|e| e.line);
+        summary
+    }
+}
+
+impl<F: FnMut(RecordBatch) + Send + 'static> ChunkSink for RecordBatchSink<F> {
+    fn push_chunk(&mut self, chunk: PipelineOutput) -> Result<(), NvParseError> {
+        self.summary.nlines += chunk.nlines;
+        let mut arrays = Vec::new();
+        for (parsed, column) in chunk.columns.into_iter().zip(&self.schema.columns) {
+            let data = ColumnData::from_words(column.ty, parsed.words, parsed.strings);
+            arrays.push(column_array(data, &parsed.validity)?);
+            self.summary.n_errors += parsed.n_errors;
+            self.summary
+                .errors
+                .extend(ParseError::from_kernel(&column.name, parsed.errors)?);
+        }
+        if self.schema.provenance {
+            let (file, row) = session::provenance_rows(&chunk.inputs, &mut self.rows_read);
+            let keys = UInt32Array::from(file);
+            arrays.push(Arc::new(DictionaryArray::<UInt32Type>::try_new(
+                keys,
+                self.files.clone(),
+            )?));
+            arrays.push(Arc::new(UInt64Array::from(row)));
+        }
+        // The row count is only needed for schemas without any columns
+        let options = RecordBatchOptions::new().with_row_count(Some(chunk.nlines as usize));
+        let batch = RecordBatch::try_new_with_options(self.arrow_schema.clone(), arrays, &options)?;
+        (self.on_batch)(batch);
+        Ok(())
+    }
+}
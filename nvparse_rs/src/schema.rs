@@ -0,0 +1,578 @@
+use std::fmt;
+use std::str::FromStr;
+
+use arrow::datatypes::{DataType, Field, TimeUnit};
+
+/// The type that a column's fields are parsed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    I32,
+    I64,
+    U32,
+    F32,
+    F64,
+    Bool,
+    /// A date laid out as given by `Dialect::date_format`, stored as the number of days since
+    /// 1970-01-01
+    Date,
+    /// A date and time laid out as given by `Dialect::timestamp_format`, stored as the number of
+    /// microseconds since 1970-01-01 00:00:00
+    Timestamp,
+    /// UTF-8 text, with the quotes around a quoted field removed
+    Utf8,
+    /// UTF-8 text stored as a dictionary of its distinct values, and the index into the
+    /// dictionary of every row. Suits columns with few distinct values.
+    Dictionary,
+    /// A fixed point number of up to `precision` digits, `scale` of which follow the decimal
+    /// separator, stored exactly as the value times 10^scale. Written as `decimal(P,S)`. Use
+    /// `ColumnType::decimal` to check the precision and scale.
+    Decimal {
+        precision: u8,
+        scale: u8,
+    },
+}
+
+/// Largest precision of a `ColumnType::Decimal`, whose values are stored as i64
+pub const MAX_DECIMAL_PRECISION: u8 = parsecsv::MAX_DECIMAL_PRECISION as u8;
+
+/// Name of the column added by `Schema::provenance` that names the input each row came from
+pub const FILE_COLUMN: &str = "__file";
+/// Name of the column added by `Schema::provenance` that numbers the rows of each input
+pub const ROW_COLUMN: &str = "__row";
+
+impl ColumnType {
+    /// A decimal with `precision` digits, `scale` of which follow the decimal separator. Fails
+    /// unless `precision` is between 1 and MAX_DECIMAL_PRECISION, and `scale` is at most
+    /// `precision`.
+    pub fn decimal(precision: u8, scale: u8) -> Result<Self, String> {
+        if precision == 0 || precision > MAX_DECIMAL_PRECISION {
+            return Err(format!(
+                "Decimal precision must be between 1 and {}, got {}",
+                MAX_DECIMAL_PRECISION, precision
+            ));
+        }
+        if scale > precision {
+            return Err(format!(
+                "Decimal scale {} is larger than its precision {}",
+                scale, precision
+            ));
+        }
+        Ok(ColumnType::Decimal { precision, scale })
+    }
+
+    /// The code used to select this type in the parsecsv kernel
+    pub(crate) fn kernel_type(&self) -> u32 {
+        match self {
+            ColumnType::I32 => parsecsv::TYPE_I32,
+            ColumnType::I64 => parsecsv::TYPE_I64,
+            ColumnType::U32 => parsecsv::TYPE_U32,
+            ColumnType::F32 => parsecsv::TYPE_F32,
+            ColumnType::F64 => parsecsv::TYPE_F64,
+            ColumnType::Bool => parsecsv::TYPE_BOOL,
+            ColumnType::Date => parsecsv::TYPE_DATE,
+            ColumnType::Timestamp => parsecsv::TYPE_TIMESTAMP,
+            ColumnType::Utf8 | ColumnType::Dictionary => parsecsv::TYPE_STRING,
+            ColumnType::Decimal { precision, scale } => {
+                parsecsv::decimal_type(*precision as u32, *scale as u32)
+            }
+        }
+    }
+
+    /// The Arrow type that columns of this type are converted to
+    pub fn arrow_type(&self) -> DataType {
+        match self {
+            ColumnType::I32 => DataType::Int32,
+            ColumnType::I64 => DataType::Int64,
+            ColumnType::U32 => DataType::UInt32,
+            ColumnType::F32 => DataType::Float32,
+            ColumnType::F64 => DataType::Float64,
+            ColumnType::Bool => DataType::Boolean,
+            ColumnType::Date => DataType::Date32,
+            ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, None),
+            ColumnType::Utf8 => DataType::Utf8,
+            ColumnType::Dictionary => {
+                DataType::Dictionary(Box::new(DataType::UInt32), Box::new(DataType::Utf8))
+            }
+            ColumnType::Decimal { precision, scale } => {
+                DataType::Decimal128(*precision, *scale as i8)
+            }
+        }
+    }
+}
+
+impl FromStr for ColumnType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lower = s.to_ascii_lowercase();
+        if let Some(params) = lower
+            .strip_prefix("decimal(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            // `decimal(P)` has a scale of 0
+            let (precision, scale) = params.split_once(',').unwrap_or((params, "0"));
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<u8>()
+                    .map_err(|_| format!("Invalid decimal type: {}", s))
+            };
+            return ColumnType::decimal(parse(precision)?, parse(scale)?);
+        }
+        match lower.as_str() {
+            "i32" => Ok(ColumnType::I32),
+            "i64" => Ok(ColumnType::I64),
+            "u32" => Ok(ColumnType::U32),
+            "f32" => Ok(ColumnType::F32),
+            "f64" => Ok(ColumnType::F64),
+            "bool" => Ok(ColumnType::Bool),
+            "date" => Ok(ColumnType::Date),
+            "timestamp" | "datetime" => Ok(ColumnType::Timestamp),
+            "string" | "str" | "utf8" => Ok(ColumnType::Utf8),
+            "dict" | "dictionary" => Ok(ColumnType::Dictionary),
+            _ => Err(format!("Unknown column type: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for ColumnType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColumnType::I32 => "i32",
+            ColumnType::I64 => "i64",
+            ColumnType::U32 => "u32",
+            ColumnType::F32 => "f32",
+            ColumnType::F64 => "f64",
+            ColumnType::Bool => "bool",
+            ColumnType::Date => "date",
+            ColumnType::Timestamp => "timestamp",
+            ColumnType::Utf8 => "string",
+            ColumnType::Dictionary => "dict",
+            ColumnType::Decimal { precision, scale } => {
+                return write!(f, "decimal({},{})", precision, scale)
+            }
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    /// Empty if the name should be taken from the header, or be `col{index}` if there is none
+    pub name: String,
+    /// Position of the field within each line, or `None` if the field should be looked up by
+    /// `name` in the header
+    pub index: Option<usize>,
+    pub ty: ColumnType,
+    /// Whether the column may contain empty fields
+    pub nullable: bool,
+}
+
+impl Column {
+    pub fn new(name: &str, index: usize, ty: ColumnType) -> Self {
+        Column {
+            name: name.to_owned(),
+            index: Some(index),
+            ty,
+            nullable: false,
+        }
+    }
+
+    /// The field at `index`, named after its header
+    pub fn at(index: usize, ty: ColumnType) -> Self {
+        Self::new("", index, ty)
+    }
+
+    /// The field named `name` in the header
+    pub fn named(name: &str, ty: ColumnType) -> Self {
+        Column {
+            name: name.to_owned(),
+            index: None,
+            ty,
+            nullable: false,
+        }
+    }
+
+    pub fn nullable(mut self, nullable: bool) -> Self {
+        self.nullable = nullable;
+        self
+    }
+
+    /// Index of the field to hand to parsecsv. Columns are resolved against the header before
+    /// parsing, so this is always known by then.
+    pub(crate) fn field_index(&self) -> u32 {
+        self.index.expect("column wasn't resolved before parsing") as u32
+    }
+}
+
+/// What ends each line of the input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Terminator {
+    /// `\n`
+    Lf,
+    /// `\r\n`. Lines ending in just `\n` are accepted as well, so this works for files with either
+    /// line ending.
+    CrLf,
+    /// Any other single byte
+    Byte(u8),
+}
+
+impl Terminator {
+    /// The byte that lines are split on
+    pub fn byte(&self) -> u8 {
+        match self {
+            Terminator::Lf | Terminator::CrLf => b'\n',
+            Terminator::Byte(b) => *b,
+        }
+    }
+
+    /// Whether a `\r` before the terminator is dropped from the line
+    pub fn strips_cr(&self) -> bool {
+        *self == Terminator::CrLf
+    }
+}
+
+impl FromStr for Terminator {
+    type Err = String;
+
+    /// Parses `lf`, `crlf`, or a single character
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "lf" => Ok(Terminator::Lf),
+            "crlf" => Ok(Terminator::CrLf),
+            _ => parse_byte(s).map(Terminator::Byte),
+        }
+    }
+}
+
+/// Parses a single ASCII character, which may be given as an escape like `\t`
+pub fn parse_byte(s: &str) -> Result<u8, String> {
+    match s {
+        "\\t" | "tab" => Ok(b'\t'),
+        "\\n" => Ok(b'\n'),
+        "\\r" => Ok(b'\r'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("Expected a single ASCII character, got {:?}", s)),
+    }
+}
+
+/// Whether the first line of the input is a header naming the columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Header {
+    Absent,
+    Present,
+    /// Guess based on the first two lines, see `header::detect`
+    Detect,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "no" | "false" | "absent" => Ok(Header::Absent),
+            "yes" | "true" | "present" => Ok(Header::Present),
+            "auto" | "detect" => Ok(Header::Detect),
+            _ => Err(format!("Unknown header mode: {}", s)),
+        }
+    }
+}
+
+/// How the fields of dates and timestamps are laid out, as a pattern of `%Y` (a 4 digit year),
+/// `%m`, `%d`, `%H`, `%M` and `%S` (2 digits each) between characters that must appear as they
+/// are, with `%%` standing for `%`. The pattern may end in `%.f`, an optional fraction of a
+/// second of up to 9 digits. Every field is at a fixed position, which keeps the GPU's parser
+/// simple.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DateFormat {
+    pattern: String,
+    /// The pattern as parsecsv's FORMAT_* items
+    items: Vec<u32>,
+}
+
+impl DateFormat {
+    /// `%Y-%m-%d`
+    pub fn date() -> Self {
+        "%Y-%m-%d".parse().unwrap()
+    }
+
+    /// `%Y-%m-%d %H:%M:%S%.f`
+    pub fn timestamp() -> Self {
+        "%Y-%m-%d %H:%M:%S%.f".parse().unwrap()
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub(crate) fn items(&self) -> &[u32] {
+        &self.items
+    }
+}
+
+impl FromStr for DateFormat {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let mut items = Vec::new();
+        let mut bytes = pattern.bytes();
+        while let Some(b) = bytes.next() {
+            if items.last() == Some(&parsecsv::FORMAT_FRACTION) {
+                return Err(format!("%.f can only end a format, got {:?}", pattern));
+            }
+            if b != b'%' {
+                items.push(b as u32);
+                continue;
+            }
+            let item = match bytes.next() {
+                Some(b'Y') => parsecsv::FORMAT_YEAR,
+                Some(b'm') => parsecsv::FORMAT_MONTH,
+                Some(b'd') => parsecsv::FORMAT_DAY,
+                Some(b'H') => parsecsv::FORMAT_HOUR,
+                Some(b'M') => parsecsv::FORMAT_MINUTE,
+                Some(b'S') => parsecsv::FORMAT_SECOND,
+                Some(b'.') if bytes.next() == Some(b'f') => parsecsv::FORMAT_FRACTION,
+                Some(b'%') => b'%' as u32,
+                _ => {
+                    return Err(format!(
+                        "Unsupported field in format {:?}, expected one of %Y, %m, %d, %H, %M, \
+                         %S, %.f or %%",
+                        pattern
+                    ))
+                }
+            };
+            if item >= parsecsv::FORMAT_YEAR && items.contains(&item) {
+                return Err(format!("Format {:?} has a field more than once", pattern));
+            }
+            items.push(item);
+        }
+        let date_fields = [
+            parsecsv::FORMAT_YEAR,
+            parsecsv::FORMAT_MONTH,
+            parsecsv::FORMAT_DAY,
+        ];
+        if !date_fields.iter().all(|field| items.contains(field)) {
+            return Err(format!("Format {:?} needs %Y, %m and %d", pattern));
+        }
+        Ok(DateFormat {
+            pattern: pattern.to_owned(),
+            items,
+        })
+    }
+}
+
+impl fmt::Display for DateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern)
+    }
+}
+
+/// How the fields of the input are laid out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dialect {
+    /// Character between the fields of a line
+    pub delimiter: u8,
+    pub terminator: Terminator,
+    /// Character that fields containing delimiters or line terminators are enclosed in. Quotes
+    /// inside a quoted field are escaped by doubling them, as in RFC 4180. `None` if fields are
+    /// never quoted.
+    pub quote: Option<u8>,
+    pub header: Header,
+    /// Character between the integer and fractional digits of floats
+    pub decimal: u8,
+    /// Layout of `ColumnType::Date` fields
+    pub date_format: DateFormat,
+    /// Layout of `ColumnType::Timestamp` fields
+    pub timestamp_format: DateFormat,
+}
+
+/// Pipe separated, with `\n` line endings and no quoting. A `\r` before the `\n` is kept as part
+/// of the last field, see `Terminator::CrLf` to drop it.
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: b'|',
+            terminator: Terminator::Lf,
+            quote: None,
+            header: Header::Absent,
+            decimal: b'.',
+            date_format: DateFormat::date(),
+            timestamp_format: DateFormat::timestamp(),
+        }
+    }
+}
+
+impl Dialect {
+    /// Comma separated and quoted as described by RFC 4180, which leaves it up to the user
+    /// whether there's a header.
+    pub fn rfc4180() -> Self {
+        Dialect {
+            delimiter: b',',
+            terminator: Terminator::CrLf,
+            quote: Some(b'"'),
+            header: Header::Detect,
+            decimal: b'.',
+            date_format: DateFormat::date(),
+            timestamp_format: DateFormat::timestamp(),
+        }
+    }
+
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    pub fn terminator(mut self, terminator: Terminator) -> Self {
+        self.terminator = terminator;
+        self
+    }
+
+    pub fn quote(mut self, quote: Option<u8>) -> Self {
+        self.quote = quote;
+        self
+    }
+
+    pub fn header(mut self, header: Header) -> Self {
+        self.header = header;
+        self
+    }
+
+    /// Sets the decimal separator, e.g. `b','` for the numbers of most European locales. It has
+    /// to differ from the delimiter, see `validate`.
+    pub fn decimal(mut self, decimal: u8) -> Self {
+        self.decimal = decimal;
+        self
+    }
+
+    pub fn date_format(mut self, format: DateFormat) -> Self {
+        self.date_format = format;
+        self
+    }
+
+    pub fn timestamp_format(mut self, format: DateFormat) -> Self {
+        self.timestamp_format = format;
+        self
+    }
+
+    /// Checks that the delimiter, line terminator, decimal separator and quote are all different
+    /// characters. A character shared by two of them would be split on or skipped over by one
+    /// and read as part of a value by the other, so the input would parse to garbage.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut roles = vec![
+            ("delimiter", self.delimiter),
+            ("line terminator", self.terminator.byte()),
+            ("decimal separator", self.decimal),
+        ];
+        if let Some(quote) = self.quote {
+            roles.push(("quote", quote));
+        }
+        for (i, (role, c)) in roles.iter().enumerate() {
+            if let Some((other, _)) = roles[..i].iter().find(|(_, other)| other == c) {
+                return Err(format!(
+                    "The {} and the {} are both {:?}",
+                    other, role, *c as char
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The layout of the fields of a `ty` column, if it's a date or timestamp. The date format
+    /// is handed to columns of other types, which don't use it.
+    pub(crate) fn datetime_format(&self, ty: ColumnType) -> &DateFormat {
+        if ty == ColumnType::Timestamp {
+            &self.timestamp_format
+        } else {
+            &self.date_format
+        }
+    }
+}
+
+/// Describes which fields of each line should be parsed, and into what types. Columns may be
+/// listed in any order and need not cover every field. Columns can be selected by name if the
+/// input has a header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    pub columns: Vec<Column>,
+    pub dialect: Dialect,
+    /// Whether to record where each row came from, see `Schema::provenance`
+    pub provenance: bool,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Self {
+        Schema {
+            columns,
+            dialect: Dialect::default(),
+            provenance: false,
+        }
+    }
+
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Records which input each row was read from, and its row number within that input. Record
+    /// batches get a `__file` column after the parsed columns naming the input, and a `__row`
+    /// column counting its rows from 0. Tables hold the same in `Table::provenance`.
+    pub fn provenance(mut self, provenance: bool) -> Self {
+        self.provenance = provenance;
+        self
+    }
+
+    /// The schema of the record batches produced by `Session::parse_batches`. Every parsed field
+    /// is nullable, since fields that fail to parse are null even in columns that aren't.
+    pub fn to_arrow(&self) -> arrow::datatypes::Schema {
+        let mut fields: Vec<_> = self
+            .columns
+            .iter()
+            .map(|column| Field::new(&column.name, column.ty.arrow_type(), true))
+            .collect();
+        if self.provenance {
+            fields.push(Field::new(
+                FILE_COLUMN,
+                ColumnType::Dictionary.arrow_type(),
+                false,
+            ));
+            fields.push(Field::new(ROW_COLUMN, DataType::UInt64, false));
+        }
+        arrow::datatypes::Schema::new(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_dialects_are_valid() {
+        assert_eq!(Dialect::default().validate(), Ok(()));
+        assert_eq!(Dialect::rfc4180().validate(), Ok(()));
+    }
+
+    #[test]
+    fn only_rfc4180_strips_carriage_returns() {
+        assert_eq!(Dialect::default().terminator, Terminator::Lf);
+        assert!(!Dialect::default().terminator.strips_cr());
+        assert!(Dialect::rfc4180().terminator.strips_cr());
+    }
+
+    #[test]
+    fn comma_decimals_need_another_delimiter() {
+        let dialect = Dialect::rfc4180().decimal(b',');
+        assert!(dialect.validate().is_err());
+        assert_eq!(dialect.delimiter(b';').validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_shared_characters() {
+        let quote_delimiter = Dialect::default().delimiter(b'"').quote(Some(b'"'));
+        assert!(quote_delimiter.validate().is_err());
+        let newline_delimiter = Dialect::default().delimiter(b'\n');
+        assert!(newline_delimiter.validate().is_err());
+        let terminator_decimal = Dialect::default().terminator(Terminator::Byte(b'.'));
+        assert!(terminator_decimal.validate().is_err());
+        // Without quoting, the quote character isn't reserved
+        assert_eq!(Dialect::default().delimiter(b'"').validate(), Ok(()));
+    }
+}
@@ -0,0 +1,409 @@
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow::record_batch::RecordBatch;
+use wgpu::{Device, Queue};
+
+use crate::bitmap::Bitmap;
+use crate::context::{AdapterSelector, GpuContext};
+use crate::decompress;
+use crate::driver::{self, ChunkSink, PipelineConfig, PipelineOutput};
+use crate::error::{NvParseError, ParseError};
+use crate::header::{self, ResolvedInputs};
+use crate::host;
+use crate::record_batch::{BatchSummary, RecordBatchSink};
+use crate::schema::{ColumnType, Schema};
+use crate::strings::Strings;
+
+/// Parsed values of a single column.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnData {
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    U32(Vec<u32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    Bool(Vec<bool>),
+    /// Days since 1970-01-01
+    Date(Vec<i32>),
+    /// Microseconds since 1970-01-01 00:00:00
+    Timestamp(Vec<i64>),
+    Utf8(Strings),
+    /// The index into `values` of every row
+    Dictionary {
+        codes: Vec<u32>,
+        values: Strings,
+    },
+    /// Every value times 10^scale
+    Decimal {
+        values: Vec<i64>,
+        precision: u8,
+        scale: u8,
+    },
+}
+
+impl ColumnData {
+    /// Decodes the words written by the parsecsv kernel for a column of type `ty`, or takes the
+    /// `strings` of a string or dictionary column
+    pub(crate) fn from_words(ty: ColumnType, words: Vec<u32>, strings: Strings) -> Self {
+        let pairs = || {
+            words
+                .chunks_exact(2)
+                .map(|w| (w[0] as u64) | ((w[1] as u64) << 32))
+        };
+        match ty {
+            ColumnType::I32 => ColumnData::I32(words.iter().map(|&w| w as i32).collect()),
+            ColumnType::I64 => ColumnData::I64(pairs().map(|v| v as i64).collect()),
+            ColumnType::U32 => ColumnData::U32(words),
+            ColumnType::F32 => ColumnData::F32(words.iter().map(|&w| f32::from_bits(w)).collect()),
+            ColumnType::F64 => ColumnData::F64(pairs().map(f64::from_bits).collect()),
+            ColumnType::Bool => ColumnData::Bool(words.iter().map(|&w| w == 1).collect()),
+            ColumnType::Date => ColumnData::Date(words.iter().map(|&w| w as i32).collect()),
+            ColumnType::Timestamp => ColumnData::Timestamp(pairs().map(|v| v as i64).collect()),
+            ColumnType::Utf8 => ColumnData::Utf8(strings),
+            ColumnType::Dictionary => ColumnData::Dictionary {
+                codes: words,
+                values: strings,
+            },
+            ColumnType::Decimal { precision, scale } => ColumnData::Decimal {
+                values: pairs().map(|v| v as i64).collect(),
+                precision,
+                scale,
+            },
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            ColumnData::I32(v) => v.len(),
+            ColumnData::I64(v) => v.len(),
+            ColumnData::U32(v) => v.len(),
+            ColumnData::F32(v) => v.len(),
+            ColumnData::F64(v) => v.len(),
+            ColumnData::Bool(v) => v.len(),
+            ColumnData::Date(v) => v.len(),
+            ColumnData::Timestamp(v) => v.len(),
+            ColumnData::Utf8(v) => v.len(),
+            ColumnData::Dictionary { codes, .. } => codes.len(),
+            ColumnData::Decimal { values, .. } => values.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The result of parsing an input: one `ColumnData` per column of the schema.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub schema: Schema,
+    pub columns: Vec<ColumnData>,
+    /// For each column, which rows hold a value. Rows that are empty in a nullable column, or
+    /// that failed to parse, are null and hold an unspecified value in `columns`.
+    pub validity: Vec<Bitmap>,
+    /// Fields that failed to parse, ordered by line. At most `MAX_ERRORS_PER_CHUNK` errors are
+    /// recorded per column for each chunk of input that is handed to the GPU.
+    pub errors: Vec<ParseError>,
+    /// Total number of fields that failed to parse, including those missing from `errors`
+    pub n_errors: u64,
+    /// Number of lines in the input, not counting the header
+    pub nlines: u64,
+    /// The fields of the header line, if the input has one
+    pub header: Option<Vec<String>>,
+    /// Where each row was read from, if the schema asks for it with `Schema::provenance`
+    pub provenance: Option<Provenance>,
+}
+
+/// Where the rows of a table were read from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// Names of the inputs, in the order they were read. Inputs that aren't files have empty
+    /// names.
+    pub files: Vec<String>,
+    /// For each row, the index in `files` of the input it was read from
+    pub file: Vec<u32>,
+    /// For each row, its row number within its input, counting from 0
+    pub row: Vec<u64>,
+}
+
+/// Expands runs of lines read from the same input, as in `PipelineOutput::inputs`, into the input
+/// and row number of every line. `rows_read` counts the rows of each input seen so far.
+pub(crate) fn provenance_rows(runs: &[(u32, u64)], rows_read: &mut [u64]) -> (Vec<u32>, Vec<u64>) {
+    let mut file = Vec::new();
+    let mut row = Vec::new();
+    for &(input, nlines) in runs {
+        let start = rows_read[input as usize];
+        file.resize(file.len() + nlines as usize, input);
+        row.extend(start..start + nlines);
+        rows_read[input as usize] += nlines;
+    }
+    (file, row)
+}
+
+/// The name of the file at `path` for `Provenance::files`
+fn file_name<P: AsRef<Path>>(path: P) -> String {
+    path.as_ref().display().to_string()
+}
+
+impl Table {
+    /// Whether row `row` of column `column` holds a value
+    pub fn is_valid(&self, column: usize, row: usize) -> bool {
+        self.validity[column].get(row)
+    }
+}
+
+enum Backend {
+    Gpu(Arc<GpuContext>),
+    /// Runs the kernels on the host, see `host`
+    Host,
+}
+
+/// Parses inputs on a GPU, or on the host. Sessions are cheap to create from a shared
+/// `GpuContext`, which holds the device and everything else that's expensive to set up.
+pub struct Session {
+    backend: Backend,
+    /// Why a session created with `new_auto` parses on the host instead of a GPU
+    fallback: Option<NvParseError>,
+}
+
+impl Session {
+    /// Creates a session on a new `GpuContext` for the default adapter
+    pub async fn new() -> Result<Self, NvParseError> {
+        Ok(Session::with_context(Arc::new(GpuContext::new().await?)))
+    }
+
+    /// Creates a session that parses on `context`'s GPU. Sessions sharing a context reuse its
+    /// compiled kernels and buffers instead of setting up their own.
+    pub fn with_context(context: Arc<GpuContext>) -> Self {
+        Session {
+            backend: Backend::Gpu(context),
+            fallback: None,
+        }
+    }
+
+    /// Creates a session that runs every kernel invocation on the host instead of a GPU. This is
+    /// slow, but works without an adapter and produces the same values as the GPU. Chunks of the
+    /// input are parsed in parallel on rayon's global thread pool.
+    pub fn new_host() -> Self {
+        Session {
+            backend: Backend::Host,
+            fallback: None,
+        }
+    }
+
+    /// Creates a session on a new `GpuContext` for the adapter picked by `selector` if it can run
+    /// the kernels, or one that parses on the host if there's no such adapter or it's missing
+    /// features they need. The reason for parsing on the host is given by `fallback_reason`.
+    pub async fn new_auto(selector: &AdapterSelector) -> Self {
+        match GpuContext::with_selector(selector).await {
+            Ok(context) => Session::with_context(Arc::new(context)),
+            Err(e) => Session {
+                fallback: Some(e),
+                ..Session::new_host()
+            },
+        }
+    }
+
+    /// Why the GPU couldn't be set up, if the session was created with `new_auto` and fell back
+    /// to parsing on the host
+    pub fn fallback_reason(&self) -> Option<&NvParseError> {
+        self.fallback.as_ref()
+    }
+
+    /// The GPU the session parses on, or `None` if it runs on the host
+    pub fn context(&self) -> Option<&Arc<GpuContext>> {
+        match &self.backend {
+            Backend::Gpu(context) => Some(context),
+            Backend::Host => None,
+        }
+    }
+
+    pub fn device(&self) -> Option<&Device> {
+        self.context().map(|context| context.device())
+    }
+
+    pub fn queue(&self) -> Option<&Queue> {
+        self.context().map(|context| context.queue())
+    }
+
+    /// Parses every line of `input` according to `schema`. The columns of the table have their
+    /// names and indices filled in from the header, if the input has one.
+    pub async fn parse(&self, input: &[u8], schema: &Schema) -> Result<Table, NvParseError> {
+        self.parse_inputs(vec![String::new()], [Ok(input)], schema)
+            .await
+    }
+
+    /// Parses every line read from `reader` according to `schema`, as it's read. Unlike `parse`,
+    /// the input doesn't have to be in memory, so it can come from a pipe or a socket.
+    pub async fn parse_reader<R: Read>(
+        &self,
+        reader: R,
+        schema: &Schema,
+    ) -> Result<Table, NvParseError> {
+        self.parse_inputs(vec![String::new()], [Ok(BufReader::new(reader))], schema)
+            .await
+    }
+
+    /// Parses the files at `paths` one after the other, as if they were a single input. Each file
+    /// is opened as it's reached, and decompressed if it's compressed, see `decompress::open`. If
+    /// the files have a header, the header of the first file is used to resolve `schema`, and
+    /// the header of every other file is skipped.
+    pub async fn parse_files<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+        schema: &Schema,
+    ) -> Result<Table, NvParseError> {
+        let names = paths.iter().map(file_name).collect();
+        let inputs = paths.iter().map(|path| decompress::open(path.as_ref()));
+        self.parse_inputs(names, inputs, schema).await
+    }
+
+    async fn parse_inputs<I, R>(
+        &self,
+        names: Vec<String>,
+        inputs: I,
+        schema: &Schema,
+    ) -> Result<Table, NvParseError>
+    where
+        I: IntoIterator<Item = io::Result<R>>,
+        R: BufRead,
+    {
+        let ResolvedInputs {
+            schema,
+            header,
+            inputs,
+        } = header::resolve_inputs(inputs, schema)?;
+        let config = PipelineConfig::new(&schema);
+        let output = self
+            .run_pipeline(inputs, &config, PipelineOutput::new(&config.columns))
+            .await?;
+        let mut columns = Vec::new();
+        let mut validity = Vec::new();
+        let mut errors = Vec::new();
+        let mut n_errors = 0;
+        for (parsed, column) in output.columns.into_iter().zip(&schema.columns) {
+            columns.push(ColumnData::from_words(
+                column.ty,
+                parsed.words,
+                parsed.strings,
+            ));
+            validity.push(parsed.validity);
+            n_errors += parsed.n_errors;
+            errors.extend(ParseError::from_kernel(&column.name, parsed.errors)?);
+        }
+        errors.sort_by_key(|e| e.line);
+        let provenance = schema.provenance.then(|| {
+            let (file, row) = provenance_rows(&output.inputs, &mut vec![0; names.len()]);
+            Provenance {
+                files: names,
+                file,
+                row,
+            }
+        });
+
+        Ok(Table {
+            schema,
+            columns,
+            validity,
+            errors,
+            n_errors,
+            nlines: output.nlines,
+            header,
+            provenance,
+        })
+    }
+
+    /// Parses every line of `input` according to `schema`, handing the values to `on_batch` as
+    /// one Arrow `RecordBatch` per chunk of input, in input order. The batches use
+    /// `Schema::to_arrow` of `BatchSummary::schema`; fields that are empty or failed to parse are
+    /// null.
+    pub async fn parse_batches<F>(
+        &self,
+        input: &[u8],
+        schema: &Schema,
+        on_batch: F,
+    ) -> Result<BatchSummary, NvParseError>
+    where
+        F: FnMut(RecordBatch) + Send + 'static,
+    {
+        self.parse_batches_inputs(vec![String::new()], [Ok(input)], schema, on_batch)
+            .await
+    }
+
+    /// Equivalent to `parse_batches`, but reads the input from `reader` as it's parsed, like
+    /// `parse_reader`.
+    pub async fn parse_batches_reader<R, F>(
+        &self,
+        reader: R,
+        schema: &Schema,
+        on_batch: F,
+    ) -> Result<BatchSummary, NvParseError>
+    where
+        R: Read,
+        F: FnMut(RecordBatch) + Send + 'static,
+    {
+        let inputs = [Ok(BufReader::new(reader))];
+        self.parse_batches_inputs(vec![String::new()], inputs, schema, on_batch)
+            .await
+    }
+
+    /// Equivalent to `parse_batches`, but reads the files at `paths` one after the other, like
+    /// `parse_files`. A record batch never holds rows from more than one file.
+    pub async fn parse_batches_files<P, F>(
+        &self,
+        paths: &[P],
+        schema: &Schema,
+        on_batch: F,
+    ) -> Result<BatchSummary, NvParseError>
+    where
+        P: AsRef<Path>,
+        F: FnMut(RecordBatch) + Send + 'static,
+    {
+        let names = paths.iter().map(file_name).collect();
+        let inputs = paths.iter().map(|path| decompress::open(path.as_ref()));
+        self.parse_batches_inputs(names, inputs, schema, on_batch)
+            .await
+    }
+
+    async fn parse_batches_inputs<I, R, F>(
+        &self,
+        names: Vec<String>,
+        inputs: I,
+        schema: &Schema,
+        on_batch: F,
+    ) -> Result<BatchSummary, NvParseError>
+    where
+        I: IntoIterator<Item = io::Result<R>>,
+        R: BufRead,
+        F: FnMut(RecordBatch) + Send + 'static,
+    {
+        let ResolvedInputs {
+            schema,
+            header,
+            inputs,
+        } = header::resolve_inputs(inputs, schema)?;
+        let config = PipelineConfig::new(&schema);
+        let sink = RecordBatchSink::new(&schema, names, on_batch);
+        let sink = self.run_pipeline(inputs, &config, sink).await?;
+        Ok(sink.finish(header))
+    }
+
+    async fn run_pipeline<I, R, S>(
+        &self,
+        inputs: I,
+        config: &PipelineConfig,
+        sink: S,
+    ) -> Result<S, NvParseError>
+    where
+        I: IntoIterator<Item = Result<R, NvParseError>>,
+        R: BufRead,
+        S: ChunkSink,
+    {
+        match &self.backend {
+            Backend::Gpu(context) => driver::run_pipeline(context, inputs, config, sink).await,
+            Backend::Host => host::run_pipeline(inputs, config, sink),
+        }
+    }
+}
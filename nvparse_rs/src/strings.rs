@@ -0,0 +1,118 @@
+//! Storage for string columns, laid out as in Arrow so that they convert without copying.
+use std::collections::HashMap;
+
+/// The strings of a column stored back to back: row `i` is `values[offsets[i]..offsets[i + 1]]`.
+/// Null rows hold empty strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Strings {
+    offsets: Vec<usize>,
+    values: Vec<u8>,
+}
+
+impl Default for Strings {
+    fn default() -> Self {
+        Strings {
+            offsets: vec![0],
+            values: Vec::new(),
+        }
+    }
+}
+
+impl Strings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, i: usize) -> &str {
+        let bytes = &self.values[self.offsets[i]..self.offsets[i + 1]];
+        std::str::from_utf8(bytes).expect("strings are checked to be UTF-8 when appended")
+    }
+
+    /// Offset of every string in `values`, followed by the length of `values`
+    pub fn offsets(&self) -> &[usize] {
+        &self.offsets
+    }
+
+    /// The bytes of every string
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> + '_ {
+        (0..self.len()).map(|i| self.get(i))
+    }
+
+    pub fn push(&mut self, value: &str) {
+        self.values.extend_from_slice(value.as_bytes());
+        self.offsets.push(self.values.len());
+    }
+
+    /// Appends the strings of the chunk that follows the ones already in `self`
+    pub(crate) fn append(&mut self, other: Strings) {
+        let base = self.values.len();
+        self.offsets
+            .extend(other.offsets[1..].iter().map(|offset| base + offset));
+        self.values.extend(other.values);
+    }
+
+    /// Appends the strings of `other` that aren't in `self` yet, treating both as dictionaries
+    /// of distinct strings. Returns the index in `self` of every string of `other`.
+    pub(crate) fn merge(&mut self, other: Strings) -> Vec<u32> {
+        if self.is_empty() {
+            *self = other;
+            return (0..self.len() as u32).collect();
+        }
+        let mut index: HashMap<String, u32> = self
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (value.to_owned(), i as u32))
+            .collect();
+        other
+            .iter()
+            .map(|value| {
+                *index.entry(value.to_owned()).or_insert_with(|| {
+                    self.push(value);
+                    (self.len() - 1) as u32
+                })
+            })
+            .collect()
+    }
+
+    /// The strings compacted by gatherstrings, row `i` being `values[offsets[i]..offsets[i + 1]]`.
+    /// Also returns the rows that aren't valid UTF-8, which are left empty.
+    pub(crate) fn from_gathered(offsets: &[u32], values: Vec<u8>) -> (Strings, Vec<usize>) {
+        // Checking the whole chunk at once is much faster, and all that's left to check is that
+        // no string starts or ends in the middle of a character.
+        if let Ok(text) = std::str::from_utf8(&values) {
+            if offsets.iter().all(|&o| text.is_char_boundary(o as usize)) {
+                let strings = Strings {
+                    offsets: offsets.iter().map(|&o| o as usize).collect(),
+                    values,
+                };
+                return (strings, Vec::new());
+            }
+        }
+
+        let mut strings = Strings::new();
+        let mut invalid = Vec::new();
+        for (row, range) in offsets.windows(2).enumerate() {
+            let bytes = &values[(range[0] as usize)..(range[1] as usize)];
+            match std::str::from_utf8(bytes) {
+                Ok(value) => strings.push(value),
+                Err(_) => {
+                    strings.push("");
+                    invalid.push(row);
+                }
+            }
+        }
+        (strings, invalid)
+    }
+}